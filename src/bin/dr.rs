@@ -52,7 +52,7 @@ fn try_main() -> Result<(), Box<dyn std::error::Error>> {
     let broken_leg = '\u{1f9b5}';
 
     let mut matcher = compile(
-        ((toppings(",").fickle()
+        (((toppings(",").fickle()
             * toppings(" ").star()
             * (sundae("vomiting") | sundae("diarrhea") | sundae("stomach pain")))
         .sun()
@@ -66,10 +66,11 @@ fn try_main() -> Result<(), Box<dyn std::error::Error>> {
                 * toppings(" ").star()
                 * (sundae("sore throat") | sundae("runny nose") | sundae("cough")))
             .sun()
-                * cheese(flu)),
+                * cheese(flu)))
+        .case_insensitive(),
     );
 
-    let (message, cost) = 'found: loop {
+    let (message, cost) = 'found: {
         for (diagnosis, response, cost) in [
             (
                 stomach_bug,
@@ -93,7 +94,7 @@ fn try_main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
 
-        break 'found ("a bone spur. Let me schedule you an MRI.", 73331);
+        ("a bone spur. Let me schedule you an MRI.", 73331)
     };
 
     print!(
@@ -146,7 +147,7 @@ fn try_main() -> Result<(), Box<dyn std::error::Error>> {
             & (NUL.neg()
                 * (sundae("af")
                     | sundae("73")
-                    | cheese('0'..='9') * sundae("a")
+                    | (cheese('0'..='9') * sundae("a"))
                     | sundae("ccc")
                     | cheese('0'..='9').fan(7))
                 * NUL.neg())
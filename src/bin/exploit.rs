@@ -1,13 +1,12 @@
 use dr::charmap::{Charmap, Charset};
-use dr::{cheese, consider, sundae, toppings, Re, EPS, NUL};
+use dr::{cheese, consider, sundae, toppings, Re, ReRef, EPS, NUL};
 use sha3::{Digest, Sha3_256};
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::{Display, Formatter, Write};
-use std::rc::Rc;
 
 #[derive(Debug)]
 struct StateImpl {
-    re: Rc<Re>,
+    re: ReRef,
     next: Charmap<State>,
     depth: usize,
     nullable: bool,
@@ -23,11 +22,11 @@ impl State {
 #[derive(Debug)]
 pub struct Matcher {
     states: Vec<StateImpl>,
-    res: HashMap<Rc<Re>, State>,
+    res: HashMap<ReRef, State>,
 }
 
 impl StateImpl {
-    fn with_depth(re: Rc<Re>, depth: usize) -> Self {
+    fn with_depth(re: ReRef, depth: usize) -> Self {
         let nullable = re.nullable();
         StateImpl {
             re,
@@ -76,74 +75,21 @@ impl CustomDisplay for dr::charmap::Charset {
     }
 }
 
-impl CustomDisplay for Rc<Re> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        use Re::*;
-        match &**self {
-            Lit(s) => write!(f, "{}", s),
-            Eps => write!(f, "ε"),
-            Nul => write!(f, "∅"),
-            Chars(cheese) => write!(f, "[{}]", CD(cheese)),
-            Neg(s) => write!(f, "¬{}", CD(s)),
-            And(ss) => {
-                write!(f, "(")?;
-                let mut first = true;
-                for s in ss.iter() {
-                    if !first {
-                        write!(f, " & ")?;
-                    }
-                    first = false;
-                    write!(f, "{}", CD(s))?;
-                }
-                write!(f, ")")
-            }
-            Alt(ss) => {
-                write!(f, "(")?;
-                let mut first = true;
-                for s in ss.iter() {
-                    if !first {
-                        write!(f, " | ")?;
-                    }
-                    first = false;
-                    write!(f, "{}", CD(s))?;
-                }
-                write!(f, ")")
-            }
-            Seq(a, b) => write!(f, "{} {}", CD(a), CD(b)),
-            Star(a) => write!(f, "({})*", CD(a)),
-            Moon(a, v, l) => write!(f, "moon({}, {}, {})", CD(a), v, l),
-            Fan(a, c) => write!(f, "fan({}, {})", CD(a), c),
-            Consider(ss, a, b, c) => {
-                write!(f, "consider((")?;
-                let mut first = true;
-                for s in ss.iter() {
-                    if !first {
-                        write!(f, ", ")?;
-                    }
-                    first = false;
-                    write!(f, "{}", CD(s))?;
-                }
-                write!(f, "), {}, {}, {})", a, b, c)
-            }
-        }
-    }
-}
-
 impl Matcher {
-    pub fn new(re: Rc<Re>) -> Self {
+    pub fn new(re: ReRef) -> Self {
         Matcher {
             states: vec![StateImpl::with_depth(re.clone(), 0)],
             res: vec![(re, State::INITIAL)].into_iter().collect(),
         }
     }
 
-    fn add_state(&mut self, re: Rc<Re>, depth: usize) -> State {
+    fn add_state(&mut self, re: ReRef, depth: usize) -> State {
         if let Some(state) = self.res.get(&re) {
-            return *state;
+            *state
         } else {
             let imp = StateImpl::with_depth(re, depth);
             let state = State(self.states.len());
-            self.res.insert(imp.re.clone(), state.clone());
+            self.res.insert(imp.re.clone(), state);
             self.states.push(imp);
             state
         }
@@ -165,14 +111,14 @@ impl Matcher {
         }
         let (cheese, next_re) = imp.re.derive(ch);
         let depth = imp.depth + 1;
-        // println!("\t{} {}", CD(cheese.clone()), CD(next_re.clone()));
+        // println!("\t{} {}", CD(cheese.clone()), next_re);
 
-        let next = self.add_state(next_re.into(), depth);
+        let next = self.add_state(next_re, depth);
         // println!("\t@ {:?}", next);
         let imp = &mut self.states[state.0];
-        let inserted = imp.next.try_insert(cheese, next.clone());
+        let inserted = imp.next.try_insert(cheese, next);
         assert!(inserted);
-        return next;
+        next
     }
 
     pub fn matches(&mut self, s: &str) -> bool {
@@ -209,7 +155,7 @@ enum Path<T> {
     Alt(Vec<Path<T>>),
 }
 
-fn path<'a>(infos: &Vec<StateInfo>, mut ptr: usize) -> Path<(usize, usize)> {
+fn path(infos: &Vec<StateInfo>, mut ptr: usize) -> Path<(usize, usize)> {
     let mut seq = VecDeque::new();
     loop {
         let info = &infos[ptr];
@@ -222,7 +168,7 @@ fn path<'a>(infos: &Vec<StateInfo>, mut ptr: usize) -> Path<(usize, usize)> {
                 let prev = *prev;
                 alts.push(Path::seq(vec![path(infos, prev), Path::Edge((prev, ptr))]));
             }
-            return Path::seq(std::iter::once(Path::Alt(alts)).chain(seq.into_iter()));
+            return Path::seq(std::iter::once(Path::Alt(alts)).chain(seq));
         }
         if let Some((_, prev)) = info.prev {
             seq.push_front(Path::Edge((prev, ptr)));
@@ -231,7 +177,7 @@ fn path<'a>(infos: &Vec<StateInfo>, mut ptr: usize) -> Path<(usize, usize)> {
             break;
         }
     }
-    Path::seq(seq.into_iter())
+    Path::seq(seq)
 }
 
 impl<T> Path<T> {
@@ -254,7 +200,7 @@ impl<T: PartialEq> Path<T> {
         use Path::*;
         match self {
             Edge(e) => Some(e),
-            Seq(ts) => ts.last().map(Self::tail).flatten(),
+            Seq(ts) => ts.last().and_then(Self::tail),
             Alt(ts) => ts
                 .iter()
                 .map(Self::tail)
@@ -281,7 +227,7 @@ impl<T: PartialEq> Path<T> {
                     match first.into_tail() {
                         Some((x, v)) => {
                             match x {
-                                Seq(init) => ts.extend(init.into_iter()),
+                                Seq(init) => ts.extend(init),
                                 _ => ts.push(x),
                             };
                             Some((Seq(ts), v))
@@ -313,10 +259,8 @@ impl<T: PartialEq> Path<T> {
                         return None;
                     }
                 }
-                match tail {
-                    None => None,
-                    Some(tail) => Some((Alt(all), tail)),
-                }
+                tail.map(|tail| (Alt(all), tail))
+            }
         }
     }
 
@@ -324,7 +268,7 @@ impl<T: PartialEq> Path<T> {
         use Path::*;
         match self {
             Edge(e) => Some(e),
-            Seq(ts) => ts.first().map(Self::head).flatten(),
+            Seq(ts) => ts.first().and_then(Self::head),
             Alt(ts) => ts
                 .iter()
                 .map(Self::head)
@@ -353,7 +297,7 @@ impl<T: PartialEq> Path<T> {
                         Some((v, x)) => {
                             let mut ts = Vec::new();
                             match x {
-                                Seq(init) => ts.extend(init.into_iter()),
+                                Seq(init) => ts.extend(init),
                                 _ => ts.push(x),
                             };
                             ts.extend(iter);
@@ -386,10 +330,8 @@ impl<T: PartialEq> Path<T> {
                         return None;
                     }
                 }
-                match head {
-                    None => None,
-                    Some(head) => Some((head, Alt(all))),
-                }
+                head.map(|head| (head, Alt(all)))
+            }
         }
     }
 
@@ -526,7 +468,7 @@ fn check_expand(m: &mut Matcher) {
     }
     m.expand(state);
     for (rng, next) in m.states[state.0].next.range_values() {
-        println!("{} {:?}\n\t{}", CD(rng), next, CD(&m.states[next.0].re));
+        println!("{} {:?}\n\t{}", CD(rng), next, m.states[next.0].re);
     }
     println!("{}", CD(m.states[state.0].transition_chars(State(6))));
 }
@@ -559,7 +501,7 @@ fn make_re() -> Re {
         & (cheese('0'..='9').moon_phase(1, 3) * cheese('a'..='f').moon_phase(2, 3))
             .moon_phase(0, 2)
         & (sundae("10") * EPS.neg()).moon_phase(0, 3)
-        & (NUL.neg() * (sundae("af") | sundae("73") | cheese('0'..='9') * sundae("a") | sundae("ccc") | cheese('0'..='9').fan(7)) * NUL.neg()).neg()
+        & (NUL.neg() * (sundae("af") | sundae("73") | (cheese('0'..='9') * sundae("a")) | sundae("ccc") | cheese('0'..='9').fan(7)) * NUL.neg()).neg()
         & (NUL.neg() * (cheese('a'..='f') * NUL.neg()).fan(6)).neg()
         // & (consider(
         //     std::iter::successors(Some('0'), |x| match x {
@@ -577,11 +519,11 @@ fn make_re() -> Re {
 
 #[test]
 fn run_check_expand() {
-    let re = Rc::from(make_re());
+    let re = ReRef::from(make_re());
     check_expand(&mut Matcher::new(re.clone()));
 }
 
-fn expand_fully<I: Into<Rc<Re>>>(re: I) -> (Matcher, Vec<StateInfo>) {
+fn expand_fully<I: Into<ReRef>>(re: I) -> (Matcher, Vec<StateInfo>) {
     let mut m = Matcher::new(re.into());
     let mut index = 0;
     let mut remaining = VecDeque::new();
@@ -636,7 +578,7 @@ fn all_results(m: &Matcher, infos: &Vec<StateInfo>) -> HashMap<u32, Vec<(String,
     let mut values = HashMap::new();
     for i in 0..m.states.len() {
         if m.states[i].nullable {
-            let p = path(&infos, i)
+            let p = path(infos, i)
                 .map(|(from, to)| m.states[from].transition_chars(State(to)))
                 .simplify();
             for p in p.all().into_iter() {
@@ -0,0 +1,243 @@
+//! Matching over raw bytes.
+//!
+//! A byte is read as the char with the same value, U+0000 to U+00FF, so a
+//! byte-level [`Re`] is an ordinary `Re` whose characters all stay below
+//! U+0100. It runs on the usual [`Matcher`](crate::Matcher) through
+//! `matches_bytes`, and byte ranges can be built with `cheese(b'a'..=b'z')`.
+//!
+//! [`Re::to_utf8`] turns a char-level `Re` into the byte-level `Re` matching the
+//! UTF-8 encodings of the same strings, so one pattern can check both `str`s
+//! and byte buffers that may not be valid UTF-8.
+
+use std::collections::HashMap;
+
+use crate::charmap::Charset;
+use crate::intern::ReRef;
+use crate::re::{sundae, Re};
+
+/// A literal byte string.
+pub fn byte_lit(bytes: &[u8]) -> Re {
+    sundae(bytes.iter().map(|&b| char::from(b)).collect::<String>())
+}
+
+/// A class matching any one of `bytes`.
+pub fn byte_set(bytes: &[u8]) -> Re {
+    Re::Chars(bytes.iter().map(|&b| char::from(b)).collect())
+}
+
+/// Matches any single byte.
+pub fn any_byte() -> Re {
+    Re::from(0u8..=0xff)
+}
+
+impl Re {
+    /// The byte-level expression matching exactly the UTF-8 encodings of the
+    /// strings this one matches. Complements only admit valid UTF-8.
+    ///
+    /// # Panics
+    ///
+    /// If a custom node does not provide its UTF-8 form.
+    pub fn to_utf8(&self) -> Re {
+        Utf8::default()
+            .encode(&ReRef::from(self.clone()))
+            .as_ref()
+            .clone()
+    }
+}
+
+#[derive(Default)]
+struct Utf8 {
+    done: HashMap<ReRef, ReRef>,
+    valid: Option<ReRef>,
+}
+
+impl Utf8 {
+    fn encode(&mut self, re: &ReRef) -> ReRef {
+        if let Some(encoded) = self.done.get(re) {
+            return encoded.clone();
+        }
+        let encoded = match &**re {
+            Re::Nul | Re::Eps => re.clone(),
+            Re::Chars(set) => encode_set(set),
+            Re::Lit(s) => ReRef::from(byte_lit(s.as_bytes())),
+            // Lengths count chars, which are not all one byte long.
+            Re::Length(min, max) => Re::repeat_rc(encode_set(&Charset::all()), *min, *max),
+            Re::LengthMod(residue, modulus) => {
+                Re::repeat_mod_rc(encode_set(&Charset::all()), *residue, *modulus)
+            }
+            // Complement has to stay within valid UTF-8. Encoding is
+            // injective, so everything else commutes with it.
+            Re::Neg(x) => {
+                let x = self.encode(x);
+                Re::and(vec![self.valid(), Re::neg_rc(x)])
+            }
+            // Interleaving the bytes of two chars never gives valid UTF-8,
+            // so the valid interleavings are the ones that keep chars whole.
+            Re::Shuffle(_) => {
+                let shuffled = re.map_children(|x| self.encode(x));
+                Re::and(vec![self.valid(), shuffled])
+            }
+            Re::Custom(node) => node
+                .to_utf8()
+                .unwrap_or_else(|| panic!("{} has no UTF-8 form", node)),
+            _ => re.map_children(|x| self.encode(x)),
+        };
+        self.done.insert(re.clone(), encoded.clone());
+        encoded
+    }
+
+    /// All valid UTF-8.
+    fn valid(&mut self) -> ReRef {
+        self.valid
+            .get_or_insert_with(|| ReRef::from(Re::Star(encode_set(&Charset::all()))))
+            .clone()
+    }
+}
+
+fn encode_set(set: &Charset) -> ReRef {
+    let mut seqs = vec![];
+    for range in set.ranges() {
+        push_sequences(range.start() as u32, range.last() as u32, &mut seqs);
+    }
+    Re::alt(seqs)
+}
+
+/// Pushes byte sequences matching the encodings of `start..=end`, each one a
+/// concatenation of byte ranges.
+fn push_sequences(start: u32, end: u32, out: &mut Vec<ReRef>) {
+    if start > end {
+        return;
+    }
+    if start < 0xe000 && end >= 0xd800 {
+        push_sequences(start, 0xd7ff, out);
+        push_sequences(0xe000, end, out);
+        return;
+    }
+    for &max in &[0x7f, 0x7ff, 0xffff] {
+        if start <= max && max < end {
+            push_sequences(start, max, out);
+            push_sequences(max + 1, end, out);
+            return;
+        }
+    }
+    // Both ends now encode to the same length. Split until every position
+    // past the first differing byte covers its whole continuation range.
+    for i in 1..4 {
+        let m = (1 << (6 * i)) - 1;
+        if start & !m != end & !m {
+            if start & m != 0 {
+                push_sequences(start, start | m, out);
+                push_sequences((start | m) + 1, end, out);
+                return;
+            }
+            if end & m != m {
+                push_sequences(start, (end & !m) - 1, out);
+                push_sequences(end & !m, end, out);
+                return;
+            }
+        }
+    }
+    let (mut lo, mut hi) = ([0; 4], [0; 4]);
+    let lo = to_char(start).encode_utf8(&mut lo).as_bytes();
+    let hi = to_char(end).encode_utf8(&mut hi).as_bytes();
+    out.push(
+        lo.iter()
+            .zip(hi)
+            .map(|(&lo, &hi)| ReRef::from(Re::from(lo..=hi)))
+            .reduce(Re::seq)
+            .expect("encodings are never empty"),
+    );
+}
+
+fn to_char(c: u32) -> char {
+    std::char::from_u32(c).expect("surrogates were split off")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cheese, compile, consider, toppings, EPS, NUL};
+
+    #[test]
+    fn byte_classes() {
+        let mut m =
+            compile(byte_lit(b"\x00\xff") * cheese(0x80u8..=0xbf).star() * byte_set(b"\n;"));
+        assert!(m.matches_bytes(b"\x00\xff\x80\xbf\n"));
+        assert!(m.matches_bytes(b"\x00\xff;"));
+        assert!(!m.matches_bytes(b"\x00\xff\x7f;"));
+        assert!(!m.matches_bytes(b"\x00\xfe;"));
+
+        let mut m = compile(any_byte().fan(3));
+        assert!(m.matches_bytes(&[1, 200, 255]));
+        assert!(!m.matches_bytes(&[1, 200]));
+    }
+
+    #[test]
+    fn utf8_matches_the_same_strings() {
+        let re = (toppings("aé€😀") | cheese('\u{7f}'..='\u{10000}'))
+            .star()
+            .neg()
+            & (sundae("ü") * NUL.neg() * cheese('\u{d7ff}'..='\u{e000}'))
+            | consider(vec![cheese('0'..='9'), sundae("€")], 1, 3) & EPS.neg()
+            | Re::length_between(5, Some(6)) & Re::length_mod(0, 5)
+            | sundae("é€").shuffle(sundae("€😀"));
+        let mut chars = compile(re.clone());
+        let mut bytes = compile(re.to_utf8());
+        for s in &[
+            "",
+            "ü\u{d7ff}",
+            "ü\u{e000}",
+            "üx\u{e000}",
+            "ü😀\u{e000}",
+            "ü\u{10000}",
+            "1",
+            "€",
+            "0€",
+            "9€€",
+            "aé€😀",
+            "\u{7ff}\u{800}",
+            "\u{ffff}ü\u{e000}",
+            "€€€€€",
+            "abcde",
+            "abcdef",
+            "é€€😀",
+            "€é😀€",
+            "€😀é€",
+        ] {
+            assert_eq!(
+                chars.matches(s),
+                bytes.matches_bytes(s.as_bytes()),
+                "{:?}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn utf8_complement_rejects_invalid_bytes() {
+        let mut m = compile(sundae("ab").neg().to_utf8());
+        assert!(m.matches_bytes("a€".as_bytes()));
+        assert!(!m.matches_bytes(b"ab"));
+        assert!(!m.matches_bytes(b"a\xe2\x82"));
+        assert!(!m.matches_bytes(b"\xed\xa0\x80"));
+        assert!(!m.matches_bytes(b"\xc0\xaf"));
+    }
+
+    #[test]
+    fn utf8_sequences_cover_every_char() {
+        let mut m = compile(cheese('\u{7e}'..std::char::MAX).to_utf8());
+        let mut buf = [0; 4];
+        for c in (0..0x11000)
+            .chain(0x10f000..0x110000)
+            .filter_map(std::char::from_u32)
+        {
+            let encoded = c.encode_utf8(&mut buf).as_bytes();
+            assert_eq!(
+                m.matches_bytes(encoded),
+                c >= '\u{7e}' && c < std::char::MAX,
+                "{:?}",
+                c
+            );
+        }
+    }
+}
@@ -0,0 +1,392 @@
+//! Extracting the spans of capture groups.
+//!
+//! Once a string is known to match, the expression is taken apart top-down and
+//! each node's span is split between its children. Splits follow POSIX
+//! disambiguation: the left side of a concatenation, and each iteration of a
+//! repetition in turn, takes the longest span that still lets the rest match.
+//! Whether a piece can match a span is answered by lazily built DFAs for the
+//! piece, run forwards, and for its reverse, run backwards.
+//!
+//! A few cases need a rule of their own:
+//!
+//! - Alternation operands are kept in canonical order, not the order they were
+//!   written in, so when several operands match the same span the first one in
+//!   that order is taken apart. The order is structural, so it does not depend
+//!   on which operand happened to be built first.
+//! - Every operand of an intersection matches the whole span, so groups in
+//!   each operand are reported.
+//! - A group under a complement never takes part in a match and is never
+//!   reported. The same goes for negative lookaround, while a group in a
+//!   positive lookaround reports what the assertion matched, as the operand of
+//!   the intersection it turns into.
+//! - A group under a shuffle does not match a single span of the text, so it
+//!   is never reported either.
+//! - A group that matches more than once, inside a repetition or because two
+//!   groups share a name, reports its last span.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::count;
+use crate::intern::ReRef;
+use crate::re::Re;
+use crate::{Cursor, Matcher, State};
+
+/// A byte range, start inclusive and end exclusive.
+pub type Span = (usize, usize);
+
+/// The spans of the named groups in one match, as byte offsets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Captures {
+    groups: Vec<(Arc<str>, Option<Span>)>,
+}
+
+impl Captures {
+    /// The span of group `name`, if it took part in the match.
+    pub fn get(&self, name: &str) -> Option<Span> {
+        self.groups
+            .iter()
+            .find(|(group, _)| &**group == name)
+            .and_then(|(_, span)| *span)
+    }
+
+    /// Every group name in order of first appearance, with its span.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<Span>)> {
+        self.groups.iter().map(|(name, span)| (&**name, *span))
+    }
+
+    fn set(&mut self, name: &Arc<str>, span: Span) {
+        if let Some(group) = self.groups.iter_mut().find(|(group, _)| group == name) {
+            group.1 = Some(span);
+        }
+    }
+}
+
+fn group_names(re: &ReRef, names: &mut Vec<Arc<str>>) {
+    if !re.has_groups() {
+        return;
+    }
+    if let Re::Group(name, _) = &**re {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    re.children().for_each(|x| group_names(x, names));
+}
+
+/// DFAs for the pieces of an expression, built as `captures` needs them.
+#[derive(Debug, Default)]
+pub(crate) struct Submatchers {
+    matchers: HashMap<ReRef, Matcher>,
+    reversed: HashMap<ReRef, Option<ReRef>>,
+}
+
+impl Submatchers {
+    fn matcher(&mut self, re: &ReRef) -> &mut Matcher {
+        self.matchers
+            .entry(re.clone())
+            .or_insert_with_key(|re| Matcher::new(re.clone()))
+    }
+
+    /// Flags, for each offset `k` in `i..=j`, whether `re` matches
+    /// `text[i..k]`. Indexed by `k - i`.
+    fn ends(&mut self, re: &ReRef, text: &str, i: usize, j: usize) -> Vec<bool> {
+        let m = self.matcher(re);
+        let mut ends = vec![false; j - i + 1];
+        let mut cursor = Cursor::START;
+        ends[0] = m.nullable(&cursor);
+        for (k, c) in text[i..j].char_indices() {
+            cursor = m.step(&cursor, &c);
+            if m.is_dead(&cursor) {
+                break;
+            }
+            ends[k + c.len_utf8()] = m.nullable(&cursor);
+        }
+        ends
+    }
+
+    /// Flags, for each offset `k` in `i..=j`, whether `re` matches
+    /// `text[k..j]`. Indexed by `k - i`. Without a reverse of `re`, each `k`
+    /// is tried forwards in turn.
+    fn starts(&mut self, re: &ReRef, text: &str, i: usize, j: usize) -> Vec<bool> {
+        let rev = self
+            .reversed
+            .entry(re.clone())
+            .or_insert_with_key(|re| re.try_reverse().map(ReRef::from))
+            .clone();
+        let mut starts = vec![false; j - i + 1];
+        let rev = match rev {
+            Some(rev) => rev,
+            None => {
+                for k in (i..=j).filter(|&k| text.is_char_boundary(k)) {
+                    starts[k - i] = self.ends(re, text, k, j)[j - k];
+                }
+                return starts;
+            }
+        };
+        let m = self.matcher(&rev);
+        let mut cursor = Cursor::START;
+        starts[j - i] = m.nullable(&cursor);
+        for (k, c) in text[i..j].char_indices().rev() {
+            cursor = m.step(&cursor, &c);
+            if m.is_dead(&cursor) {
+                break;
+            }
+            starts[k] = m.nullable(&cursor);
+        }
+        starts
+    }
+
+    /// The largest `k` in `i + 1..=j` such that `a` matches `text[i..k]` and
+    /// `b` matches `text[k..j]`, or `i` itself if `nonempty` is false and
+    /// nothing longer works.
+    fn split(
+        &mut self,
+        a: &ReRef,
+        b: &ReRef,
+        text: &str,
+        (i, j): Span,
+        nonempty: bool,
+    ) -> Option<usize> {
+        let ends = self.ends(a, text, i, j);
+        let starts = self.starts(b, text, i, j);
+        let min = if nonempty { 1 } else { 0 };
+        (min..=j - i)
+            .rev()
+            .find(|&k| ends[k] && starts[k])
+            .map(|k| i + k)
+    }
+
+    /// Splits `text[i..j]`, which `re` is known to match, recording groups.
+    fn walk(&mut self, re: &ReRef, text: &str, (i, j): Span, caps: &mut Captures) {
+        if !re.has_groups() {
+            return;
+        }
+        match &**re {
+            Re::Nul
+            | Re::Eps
+            | Re::Chars(_)
+            | Re::Lit(_)
+            | Re::Neg(_)
+            | Re::Look(_, _)
+            | Re::Custom(_)
+            | Re::Length(_, _)
+            | Re::LengthMod(_, _)
+            | Re::Shuffle(_)
+            | Re::Ambiguous(_, _, _) => (),
+            Re::Group(name, a) => {
+                caps.set(name, (i, j));
+                self.walk(a, text, (i, j), caps);
+            }
+            Re::And(res) => {
+                for x in res.iter() {
+                    self.walk(x, text, (i, j), caps);
+                }
+            }
+            Re::Alt(res) => {
+                let x = res
+                    .iter()
+                    .find(|x| self.ends(x, text, i, j)[j - i])
+                    .expect("some operand matches")
+                    .clone();
+                self.walk(&x, text, (i, j), caps);
+            }
+            Re::Seq(a, b) => {
+                let k = self
+                    .split(a, b, text, (i, j), false)
+                    .expect("some split matches");
+                self.walk(a, text, (i, k), caps);
+                self.walk(b, text, (k, j), caps);
+            }
+            // The rest are repetitions of pieces that are never empty, so they
+            // are taken apart one piece at a time, leftmost piece longest.
+            _ => {
+                let (mut rest, mut i) = (re.clone(), i);
+                while let Some(pieces) = pieces(&rest) {
+                    if i == j {
+                        return;
+                    }
+                    let mut best: Option<(usize, ReRef, ReRef)> = None;
+                    for (piece, next) in pieces {
+                        if let Some(k) = self.split(&piece, &next, text, (i, j), true) {
+                            if best.as_ref().is_none_or(|best| k > best.0) {
+                                best = Some((k, piece, next));
+                            }
+                        }
+                    }
+                    let (k, piece, next) = best.expect("some piece matches");
+                    self.walk(&piece, text, (i, k), caps);
+                    i = k;
+                    rest = next;
+                }
+                // The last piece of a `Fan` may be empty.
+                self.walk(&rest, text, (i, j), caps);
+            }
+        }
+    }
+}
+
+/// The ways a repetition can start: each first piece, paired with what has
+/// to match after it. `None` if `re` is not a repetition.
+fn pieces(re: &ReRef) -> Option<Vec<(ReRef, ReRef)>> {
+    let pieces = match &**re {
+        Re::Star(a)
+        | Re::Fan(a, _)
+        | Re::Repeat(a, _, _)
+        | Re::Moon(a, _, _)
+        | Re::RepeatMod(a, _, _) => vec![(a.clone(), re.repeat_next())],
+        Re::Consider(choices, ..) | Re::ConsiderLsd(choices, ..) => choices
+            .iter()
+            .enumerate()
+            .map(|(index, choice)| (choice.clone(), re.consider_next(index)))
+            .collect(),
+        Re::Checksum(rule, pos, sums, targets) => rule
+            .choices
+            .iter()
+            .enumerate()
+            .map(|(index, choice)| (choice.clone(), rule.next(*pos, sums, targets, index)))
+            .collect(),
+        // A residual that is not empty is the rest of the current piece, and
+        // one that may be empty can also be skipped to start the next piece.
+        Re::Count(a, counters) => {
+            let mut pieces = vec![];
+            for (x, counts) in counters.iter() {
+                if **x != Re::Eps {
+                    pieces.push((x.clone(), count::counted(a.clone(), counts.clone())));
+                }
+                if x.nullable() {
+                    let rest = count::counted(a.clone(), counts.decrement());
+                    pieces.push((a.clone(), rest));
+                }
+            }
+            pieces
+        }
+        _ => return None,
+    };
+    Some(pieces)
+}
+
+impl Matcher {
+    /// Matches `s` and, if it matches, reports where each capture group
+    /// matched. See [`crate::captures`] for how ambiguity is resolved.
+    pub fn captures(&mut self, s: &str) -> Option<Captures> {
+        if !self.matches(s) {
+            return None;
+        }
+        let re = self.states[State::INITIAL.0].re.clone();
+        let mut names = vec![];
+        group_names(&re, &mut names);
+        let mut caps = Captures {
+            groups: names.into_iter().map(|name| (name, None)).collect(),
+        };
+        self.submatchers.walk(&re, s, (0, s.len()), &mut caps);
+        Some(caps)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cheese, compile, consider, sundae, toppings, EPS, NUL};
+
+    fn spans(re: Re, s: &str) -> Option<Vec<(String, Option<Span>)>> {
+        let caps = compile(re).captures(s)?;
+        Some(
+            caps.iter()
+                .map(|(name, span)| (name.to_string(), span))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn posix_leftmost_longest() {
+        let a = cheese('a');
+        let re = a.clone().star().capture("x") * a.clone().star().capture("y");
+        assert_eq!(
+            spans(re, "aaa"),
+            Some(vec![("x".into(), Some((0, 3))), ("y".into(), Some((3, 3)))])
+        );
+
+        let re = (sundae("ab") | cheese('a')).capture("x").star() * (sundae("b") | EPS);
+        let caps = compile(re).captures("aba").unwrap();
+        assert_eq!(caps.get("x"), Some((2, 3)));
+
+        assert_eq!(compile(cheese('a').capture("x")).captures("b"), None);
+        let caps = compile(cheese('a').capture("x").star())
+            .captures("")
+            .unwrap();
+        assert_eq!(caps.get("x"), None);
+    }
+
+    #[test]
+    fn alternatives_in_canonical_order() {
+        // `p` is built first and `r` last, yet both times the operand that
+        // sorts first is reported.
+        let re = cheese('a').capture("p") | cheese('a').capture("q");
+        let caps = compile(re).captures("a").unwrap();
+        assert_eq!((caps.get("p"), caps.get("q")), (Some((0, 1)), None));
+        let re = cheese('a').capture("s") | cheese('a').capture("r");
+        let caps = compile(re).captures("a").unwrap();
+        assert_eq!((caps.get("r"), caps.get("s")), (Some((0, 1)), None));
+    }
+
+    #[test]
+    fn insurance_fields() {
+        let digits = || cheese('0'..='9').sun();
+        let hex = || cheese('a'..='f').sun();
+        let re = (digits().capture("digits") * hex().capture("letters")).sun()
+            & (sundae("10") * NUL.neg()).capture("all");
+        let caps = compile(re).captures("10174cdbf10810c").unwrap();
+        assert_eq!(caps.get("digits"), Some((9, 14)));
+        assert_eq!(caps.get("letters"), Some((14, 15)));
+        assert_eq!(caps.get("all"), Some((0, 15)));
+    }
+
+    #[test]
+    fn counted_repetitions() {
+        let re = consider(vec![toppings("ab").capture("one"), sundae("ab")], 1, 2);
+        let caps = compile(re).captures("aab").unwrap();
+        assert_eq!(caps.get("one"), Some((0, 1)));
+
+        let re = (cheese('a') * cheese('b').fickle().capture("b")).fan(2);
+        let caps = compile(re).captures("aba").unwrap();
+        assert_eq!(caps.get("b"), Some((3, 3)));
+
+        let re = cheese('a').fickle().capture("a").repeat(2, Some(3));
+        let caps = compile(re).captures("aa").unwrap();
+        assert_eq!(caps.get("a"), Some((1, 2)));
+
+        let re = cheese('x').capture("x").moon(2) * cheese('y').star();
+        let caps = compile(re).captures("xxxyy").unwrap();
+        assert_eq!(caps.get("x"), Some((2, 3)));
+
+        let re = Re::parse("%count((?<d>[0-9]),ε,x; 2; 1-)").unwrap();
+        let mut m = compile(re);
+        assert_eq!(m.captures("12").unwrap().get("d"), Some((1, 2)));
+        assert_eq!(m.captures("x345").unwrap().get("d"), Some((3, 4)));
+        assert!(m.captures("x").is_none());
+    }
+
+    #[test]
+    fn groups_in_lookahead() {
+        let re = cheese('a').star()
+            * crate::lookahead(cheese('b').capture("peek"))
+            * NUL.neg().capture("rest");
+        let caps = compile(re).captures("aabc").unwrap();
+        assert_eq!(caps.get("peek"), Some((2, 3)));
+        assert_eq!(caps.get("rest"), Some((2, 4)));
+    }
+
+    #[test]
+    fn groups_under_complement_never_match() {
+        let re = sundae("ab").capture("no").neg() & cheese('a').star().capture("yes");
+        let caps = compile(re).captures("aaa").unwrap();
+        assert_eq!(
+            caps.iter().collect::<Vec<_>>().len(),
+            2,
+            "both names are listed"
+        );
+        assert_eq!(caps.get("no"), None);
+        assert_eq!(caps.get("yes"), Some((0, 3)));
+    }
+}
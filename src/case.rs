@@ -0,0 +1,198 @@
+//! Case-insensitive matching.
+//!
+//! Two chars are the same up to case when they are linked by a chain of
+//! single-char `to_lowercase`/`to_uppercase` mappings. Mappings that expand to
+//! several chars, like `ß` to `SS`, are left out, which is the usual simple
+//! case folding.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::charmap::Charset;
+use crate::intern::ReRef;
+use crate::re::{sundae, Re};
+
+struct Folding {
+    /// Every char with a case mapping, sorted, with the index of its class.
+    chars: Vec<(char, usize)>,
+    classes: Vec<Vec<char>>,
+}
+
+fn single(mut it: impl Iterator<Item = char>) -> Option<char> {
+    match (it.next(), it.next()) {
+        (Some(ch), None) => Some(ch),
+        _ => None,
+    }
+}
+
+fn find(parent: &mut HashMap<char, char>, ch: char) -> char {
+    let up = *parent.entry(ch).or_insert(ch);
+    if up == ch {
+        ch
+    } else {
+        let root = find(parent, up);
+        parent.insert(ch, root);
+        root
+    }
+}
+
+fn folding() -> &'static Folding {
+    static FOLDING: OnceLock<Folding> = OnceLock::new();
+    FOLDING.get_or_init(|| {
+        let mut parent = HashMap::new();
+        for ch in '\0'..=std::char::MAX {
+            for other in [single(ch.to_lowercase()), single(ch.to_uppercase())]
+                .iter()
+                .flatten()
+            {
+                if *other != ch {
+                    let (a, b) = (find(&mut parent, ch), find(&mut parent, *other));
+                    parent.insert(a, b);
+                }
+            }
+        }
+
+        let mut chars: Vec<char> = parent.keys().cloned().collect();
+        chars.sort_unstable();
+        let mut index = HashMap::new();
+        let mut classes: Vec<Vec<char>> = vec![];
+        let chars = chars
+            .into_iter()
+            .map(|ch| {
+                let root = find(&mut parent, ch);
+                let class = *index.entry(root).or_insert_with(|| {
+                    classes.push(vec![]);
+                    classes.len() - 1
+                });
+                classes[class].push(ch);
+                (ch, class)
+            })
+            .collect();
+        Folding { chars, classes }
+    })
+}
+
+impl Folding {
+    fn class(&self, ch: char) -> Option<&[char]> {
+        self.chars
+            .binary_search_by_key(&ch, |&(ch, _)| ch)
+            .ok()
+            .map(|i| &self.classes[self.chars[i].1][..])
+    }
+
+    fn close(&self, set: &Charset) -> Charset {
+        let mut closed = set.clone();
+        for &(ch, class) in &self.chars {
+            if set.contains(&ch) {
+                for &other in &self.classes[class] {
+                    closed.insert_char(other);
+                }
+            }
+        }
+        closed
+    }
+}
+
+impl Re {
+    /// Matches the same strings up to case. Every class and literal is widened
+    /// to all case variants of its chars, so a complement rejects every case
+    /// variant of what it excludes.
+    ///
+    /// # Panics
+    ///
+    /// If a custom node does not provide its case-insensitive form.
+    pub fn case_insensitive(&self) -> Re {
+        let mut done = HashMap::new();
+        fold(&mut done, &ReRef::from(self.clone())).as_ref().clone()
+    }
+}
+
+fn fold(done: &mut HashMap<ReRef, ReRef>, re: &ReRef) -> ReRef {
+    if let Some(folded) = done.get(re) {
+        return folded.clone();
+    }
+    let folded = match &**re {
+        Re::Nul | Re::Eps => re.clone(),
+        Re::Chars(set) => ReRef::from(Re::Chars(folding().close(set))),
+        Re::Lit(s) => {
+            // Runs of chars without case stay literals.
+            let mut parts = vec![];
+            let mut run = String::new();
+            for ch in s.chars() {
+                match folding().class(ch) {
+                    Some(class) => {
+                        if !run.is_empty() {
+                            parts.push(ReRef::from(sundae(std::mem::take(&mut run))));
+                        }
+                        parts.push(ReRef::from(Re::Chars(class.iter().cloned().collect())));
+                    }
+                    None => run.push(ch),
+                }
+            }
+            if parts.is_empty() {
+                re.clone()
+            } else {
+                if !run.is_empty() {
+                    parts.push(ReRef::from(sundae(run)));
+                }
+                parts
+                    .into_iter()
+                    .rev()
+                    .reduce(|b, a| Re::seq(a, b))
+                    .expect("checked non-empty")
+            }
+        }
+        Re::Custom(node) => node
+            .case_insensitive()
+            .unwrap_or_else(|| panic!("{} has no case-insensitive form", node)),
+        _ => re.map_children(|x| fold(done, x)),
+    };
+    done.insert(re.clone(), folded.clone());
+    folded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cheese, compile, consider, toppings, EPS, NUL};
+
+    #[test]
+    fn classes_follow_simple_folding() {
+        let f = folding();
+        let mut k = f.class('k').unwrap().to_vec();
+        k.sort_unstable();
+        assert_eq!(k, vec!['K', 'k', '\u{212a}']);
+        assert_eq!(f.class('Σ').unwrap().len(), 3);
+        assert!(f.class('1').is_none());
+        // `ß` only uppercases to two chars, but `ẞ` lowercases to it.
+        assert_eq!(f.class('ß').unwrap().len(), 2);
+    }
+
+    #[test]
+    fn literals_ignore_case() {
+        let mut m = compile(
+            (sundae("sore throat") | sundae("runny nose") | sundae("cough")).case_insensitive(),
+        );
+        assert!(m.matches("Sore Throat"));
+        assert!(m.matches("RUNNY NOSE"));
+        assert!(m.matches("cOUgh"));
+        assert!(!m.matches("sore  throat"));
+        assert_eq!(sundae("12 ").case_insensitive(), sundae("12 "));
+    }
+
+    #[test]
+    fn works_through_combinators() {
+        let re = (sundae("ab").neg() & toppings("abc").star())
+            | consider(vec![cheese('x'), cheese('y')], 1, 2).moon(2);
+        let mut m = compile(re.case_insensitive());
+        assert!(m.matches("CAB"));
+        assert!(!m.matches("aB"));
+        assert!(!m.matches("Ab"));
+        assert!(m.matches("yY"));
+        assert!(!m.matches("Xx"));
+
+        let mut m = compile((cheese('a'..='z') * NUL.neg()).neg().case_insensitive() & EPS.neg());
+        assert!(m.matches("1a"));
+        assert!(!m.matches("Qa"));
+    }
+}
@@ -17,6 +17,12 @@ pub struct Charmap<V> {
     data: BTreeMap<CharRange, V>,
 }
 
+impl<V> Default for Charmap<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<V> Charmap<V> {
     pub fn new() -> Self {
         Self {
@@ -38,7 +44,7 @@ impl<V> Charmap<V> {
                 return (self.data.remove_entry(&last_key), right);
             }
         }
-        return (None, right);
+        (None, right)
     }
 
     pub fn intersects(&self, key: &CharRange) -> bool {
@@ -69,7 +75,7 @@ impl<V> Charmap<V> {
             }
             self.data.append(&mut keep.data);
         }
-        self.data.insert(key.into(), value);
+        self.data.insert(key, value);
     }
 
     pub fn get_in_or_out(&self, ch: &char) -> InOrOut<(&CharRange, &V), CharRange> {
@@ -155,7 +161,7 @@ impl<V: Clone> Charmap<V> {
             assert!(start < lrange.end() || start < rrange.end());
             if start >= lrange.start() && start >= rrange.start() {
                 let range = start..end;
-                let value = combine(&range, &lvalue, &rvalue);
+                let value = combine(&range, lvalue, rvalue);
                 insert_and_increment(&mut self.data, &mut position, range, value);
                 if end == lrange.end() {
                     lelem = liter.next();
@@ -206,7 +212,7 @@ impl<T> FromIterator<(CharRange, T)> for Charmap<T> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Charset(Charmap<()>);
 
 pub struct Ranges<'a, V> {
@@ -235,13 +241,12 @@ impl<'a, V> Iterator for Holes<'a, V> {
 
     fn next(&mut self) -> Option<Self::Item> {
         for range in &mut self.inner {
-            if range.start() > self.start {
-                let result = CharRange::from(self.start..range.start());
-                self.start = range.end();
-                return Some(result);
+            let start = mem::replace(&mut self.start, range.end());
+            if range.start() > start {
+                return Some(CharRange::from(start..range.start()));
             }
         }
-        let last = CharRange::start_from(self.start);
+        let last = CharRange::start_from(mem::replace(&mut self.start, std::char::MAX));
         if !last.is_empty() {
             return Some(last);
         }
@@ -315,6 +320,10 @@ impl Charset {
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.0.data.is_empty()
+    }
+
     pub fn len(&self) -> usize {
         self.ranges()
             .map(|rng| rng.end() as u32 - rng.start() as u32)
@@ -393,7 +402,7 @@ fn charset_membership() {
     assert!("\0abc0932".chars().all(|c| set.contains(&c)));
 
     let set: Charset = "abcd".chars().collect();
-    assert!("\00932".chars().all(|c| !set.contains(&c)));
+    assert!("\x000932".chars().all(|c| !set.contains(&c)));
     assert!("abcd".chars().all(|c| set.contains(&c)));
 
     let set: Charset = vec![('a'..'t'), ('B'..'Y')]
@@ -402,4 +411,14 @@ fn charset_membership() {
         .collect();
     assert!("afghBL".chars().all(|c| set.contains(&c)));
     assert!("zZA932".chars().all(|c| !set.contains(&c)));
+
+    let holes: Vec<_> = set.holes().collect();
+    assert_eq!(holes.len(), 3);
+    let other: Charset = "\0cdz".chars().collect();
+    let both = &set & &other;
+    assert!("cd".chars().all(|c| both.contains(&c)));
+    assert!("\0abzB".chars().all(|c| !both.contains(&c)));
+    let a: Charset = "a".chars().collect();
+    let b: Charset = "b".chars().collect();
+    assert!((&a & &b).is_empty());
 }
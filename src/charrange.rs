@@ -1,11 +1,11 @@
 use std::cmp::Ordering;
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
 use std::ops::{BitAnd, Range, RangeInclusive};
 
 const MINCHAR: char = '\0';
 const MAXCHAR: char = std::char::MAX;
 
-#[derive(Clone, Hash, Debug)]
+#[derive(Clone, Debug)]
 pub struct CharRange(Range<char>);
 
 impl Ord for CharRange {
@@ -28,8 +28,24 @@ impl PartialEq for CharRange {
 
 impl Eq for CharRange {}
 
+impl Hash for CharRange {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.0.start, self.0.end).hash(state)
+    }
+}
+
 fn next_char(r: char) -> char {
-    std::char::from_u32(r as u32 + 1).unwrap()
+    match r {
+        '\u{d7ff}' => '\u{e000}',
+        _ => std::char::from_u32(r as u32 + 1).unwrap(),
+    }
+}
+
+fn prev_char(r: char) -> char {
+    match r {
+        '\u{e000}' => '\u{d7ff}',
+        _ => std::char::from_u32(r as u32 - 1).unwrap(),
+    }
 }
 
 impl From<char> for CharRange {
@@ -50,6 +66,19 @@ impl From<RangeInclusive<char>> for CharRange {
     }
 }
 
+/// Bytes are read as the chars with the same value.
+impl From<u8> for CharRange {
+    fn from(b: u8) -> CharRange {
+        CharRange::from(char::from(b))
+    }
+}
+
+impl From<RangeInclusive<u8>> for CharRange {
+    fn from(r: RangeInclusive<u8>) -> CharRange {
+        CharRange::from(char::from(*r.start())..=char::from(*r.end()))
+    }
+}
+
 impl CharRange {
     pub fn at(c: char) -> CharRange {
         CharRange(c..c)
@@ -87,6 +116,23 @@ impl CharRange {
         self.0.end
     }
 
+    /// The last character in a non-empty range.
+    pub fn last(&self) -> char {
+        prev_char(self.0.end)
+    }
+
+    /// The number of characters in the range, counting the surrogate gap.
+    pub fn len(&self) -> usize {
+        (self.0.end as u32).saturating_sub(self.0.start as u32) as usize
+    }
+
+    /// The number of characters in the range, leaving out the surrogate gap.
+    pub fn char_count(&self) -> usize {
+        let (start, end) = (self.0.start as u32, self.0.end as u32);
+        let gap = end.min(0xe000).saturating_sub(start.max(0xd800));
+        self.len() - gap as usize
+    }
+
     pub fn intersects(&self, other: &CharRange) -> bool {
         char::max(self.0.start, other.0.start) < char::min(self.0.end, other.0.end)
     }
@@ -114,3 +160,13 @@ fn order_of_ranges() {
     let r2 = CharRange::from('f'..MAXCHAR);
     assert!(r1 < r2);
 }
+
+#[test]
+fn counting_chars() {
+    assert_eq!(CharRange::from('a'..='z').char_count(), 26);
+    let across = CharRange::from('\u{d7fe}'..='\u{e001}');
+    assert_eq!(across.len(), 0x804);
+    assert_eq!(across.char_count(), 4);
+    assert_eq!(CharRange::all().char_count(), 0x10ffff - 0x800);
+    assert_eq!(CharRange::empty().char_count(), 0);
+}
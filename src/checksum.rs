@@ -0,0 +1,269 @@
+//! Check digits computed as a sum of per-digit contributions.
+//!
+//! [`consider`](crate::consider) reads its digits as one positional number.
+//! Most check digit schemes instead add up what each digit contributes, where
+//! the contribution depends on the digit and on a slot that cycles with its
+//! position: Luhn doubles every second digit from the right, ISBN and EAN
+//! weight digits from the left, and a plain digit sum has a single slot.
+//!
+//! A [`ChecksumRule`] holds the contribution table and which end the slots are
+//! counted from. Slots counted from the left are known as soon as a digit is
+//! read. Slots counted from the right depend on how many digits are still to
+//! come, so the `Checksum` node keeps one running sum for every possible total
+//! number of digits modulo the period, and picks the right one at the end.
+
+use std::sync::Arc;
+
+use crate::charrange::CharRange;
+use crate::intern::ReRef;
+use crate::re::{Re, Res};
+use crate::residue::{add_mod, mul_mod, sub_mod};
+
+/// How the digits of a checksum are weighted.
+#[derive(Clone, Eq, PartialEq, PartialOrd, Ord, Hash, Debug)]
+pub struct ChecksumRule {
+    pub(crate) choices: Res,
+    /// `table[slot][index]` is what choice `index` adds to the sum when read
+    /// in `slot`.
+    pub(crate) table: Vec<Vec<usize>>,
+    pub(crate) from_right: bool,
+    pub(crate) within: usize,
+}
+
+impl ChecksumRule {
+    pub(crate) fn period(&self) -> usize {
+        self.table.len()
+    }
+
+    /// The slot of the piece read at `pos` if the total number of pieces is
+    /// `total`, both modulo the period.
+    fn slot(&self, pos: usize, total: usize) -> usize {
+        if self.from_right {
+            let p = self.period();
+            (total + 2 * p - 1 - pos) % p
+        } else {
+            pos
+        }
+    }
+
+    /// The node after reading choice `index` at `pos`.
+    pub(crate) fn next(
+        self: &Arc<Self>,
+        pos: usize,
+        sums: &[usize],
+        targets: &[usize],
+        index: usize,
+    ) -> ReRef {
+        let sums = (0..self.period())
+            .map(|total| {
+                let add = self.table[self.slot(pos, total)][index];
+                add_mod(sums[total] as u128, add as u128, self.within as u128) as usize
+            })
+            .collect();
+        ReRef::from(Re::Checksum(
+            self.clone(),
+            (pos + 1) % self.period(),
+            sums,
+            targets.to_vec(),
+        ))
+    }
+
+    pub(crate) fn derive(
+        self: &Arc<Self>,
+        pos: usize,
+        sums: &[usize],
+        targets: &[usize],
+        ch: &char,
+    ) -> (CharRange, ReRef) {
+        let (range, derived) = self.choices.derive(ch);
+        (
+            range,
+            Re::alt(
+                derived
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, re)| Re::seq(re, self.next(pos, sums, targets, index))),
+            ),
+        )
+    }
+
+    /// The node matching the reverses of what `Checksum(self, pos, sums,
+    /// targets)` matches, given the reversed choices. The pieces still to come
+    /// are read from the other end, so the slots are counted from the other
+    /// end too, and what they have to add up to is the target less the sum so
+    /// far.
+    pub(crate) fn reverse(
+        &self,
+        choices: Res,
+        pos: usize,
+        sums: &[usize],
+        targets: &[usize],
+    ) -> Re {
+        let p = self.period();
+        let table = if self.from_right {
+            self.table.clone()
+        } else {
+            (0..p).map(|s| self.table[(s + pos) % p].clone()).collect()
+        };
+        let targets = (0..p)
+            .map(|r| {
+                let total = (pos + r) % p;
+                sub_mod(
+                    targets[total] as u128,
+                    sums[total] as u128,
+                    self.within as u128,
+                ) as usize
+            })
+            .collect();
+        let rule = ChecksumRule {
+            choices,
+            table,
+            from_right: !self.from_right,
+            within: self.within,
+        };
+        Re::Checksum(Arc::new(rule), 0, vec![0; p], targets)
+    }
+}
+
+fn checksum<I: IntoIterator<Item = Re>>(
+    digits: I,
+    table: Vec<Vec<usize>>,
+    from_right: bool,
+    target: usize,
+    within: usize,
+) -> Re {
+    assert!(within > 0, "the modulus must not be zero");
+    assert!(!table.is_empty(), "there must be at least one weight");
+    let p = table.len();
+    let rule = ChecksumRule {
+        choices: Res::from(digits.into_iter().map(ReRef::from).collect::<Vec<_>>()),
+        table: table
+            .into_iter()
+            .map(|row| row.into_iter().map(|x| x % within).collect())
+            .collect(),
+        from_right,
+        within,
+    };
+    Re::Checksum(Arc::new(rule), 0, vec![0; p], vec![target % within; p])
+}
+
+/// Matches sequences of `digits` whose indices add up to `target` modulo
+/// `within`.
+///
+/// # Panics
+///
+/// If `within` is zero.
+pub fn digit_sum<I: IntoIterator<Item = Re>>(digits: I, target: usize, within: usize) -> Re {
+    let digits: Vec<_> = digits.into_iter().collect();
+    let row = (0..digits.len()).collect();
+    checksum(digits, vec![row], false, target, within)
+}
+
+/// Matches sequences of `digits` where the sum of each index times its
+/// weight is `target` modulo `within`. The first digit gets the first weight,
+/// and the weights start over when they run out.
+///
+/// # Panics
+///
+/// If `within` is zero or there are no `weights`.
+pub fn weighted_sum<I: IntoIterator<Item = Re>>(
+    digits: I,
+    weights: &[usize],
+    target: usize,
+    within: usize,
+) -> Re {
+    let digits: Vec<_> = digits.into_iter().collect();
+    assert!(within > 0, "the modulus must not be zero");
+    let table = weights
+        .iter()
+        .map(|&w| {
+            (0..digits.len())
+                .map(|index| mul_mod(w as u128, index as u128, within as u128) as usize)
+                .collect()
+        })
+        .collect();
+    checksum(digits, table, false, target, within)
+}
+
+/// Matches sequences of `digits` that pass the Luhn check: counting from the
+/// right, every second index is doubled, with the digits of the result added
+/// up, and the total is a multiple of 10.
+pub fn luhn<I: IntoIterator<Item = Re>>(digits: I) -> Re {
+    let digits: Vec<_> = digits.into_iter().collect();
+    let plain = (0..digits.len()).collect();
+    let doubled = (0..digits.len())
+        .map(|index| (2 * index) / 10 + (2 * index) % 10)
+        .collect();
+    checksum(digits, vec![plain, doubled], true, 0, 10)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cheese, compile, sundae};
+
+    fn decimal() -> Vec<Re> {
+        ('0'..='9').map(cheese).collect()
+    }
+
+    #[test]
+    fn luhn_numbers() {
+        let mut m = compile(luhn(decimal()) & cheese('0'..='9').fan(16));
+        assert!(m.matches("4539578763621486"));
+        assert!(m.matches("4111111111111111"));
+        assert!(!m.matches("4111111111111112"));
+        assert!(!m.matches("4539578763621468"));
+
+        let mut m = compile(luhn(decimal()));
+        assert!(m.matches("79927398713"));
+        assert!(!m.matches("79927398710"));
+        assert!(m.matches("0"));
+        assert!(m.matches("18"));
+        assert!(!m.matches("81"));
+    }
+
+    #[test]
+    fn isbn_weights() {
+        let mut isbn13 = compile(weighted_sum(decimal(), &[1, 3], 0, 10));
+        assert!(isbn13.matches("9780306406157"));
+        assert!(!isbn13.matches("9780306406158"));
+
+        let mut isbn10 = decimal();
+        isbn10.push(cheese('X'));
+        let mut isbn10 = compile(
+            weighted_sum(isbn10, &[10, 9, 8, 7, 6, 5, 4, 3, 2, 1], 0, 11)
+                & (cheese('0'..='9').fan(9) * (cheese('0'..='9') | cheese('X'))),
+        );
+        assert!(isbn10.matches("0306406152"));
+        assert!(isbn10.matches("080442957X"));
+        assert!(!isbn10.matches("0306406153"));
+    }
+
+    #[test]
+    fn digit_sums_and_multi_char_digits() {
+        let mut m = compile(digit_sum(decimal(), 0, 9));
+        assert!(m.matches("12345678"));
+        assert!(!m.matches("1234567"));
+
+        let mut m = compile(digit_sum(
+            vec![sundae("zero"), sundae("one"), sundae("two")],
+            1,
+            3,
+        ));
+        assert!(m.matches("twotwo"));
+        assert!(m.matches("onezerozero"));
+        assert!(!m.matches("onetwo"));
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn huge_weights_and_moduli() {
+        // 2^64 - 1 leaves 1 when divided by 7.
+        let mut m = compile(weighted_sum(decimal(), &[usize::MAX], 0, 7));
+        assert!(m.matches("16"));
+        assert!(!m.matches("15"));
+        let mut m = compile(digit_sum(decimal(), 18, usize::MAX));
+        assert!(m.matches("99"));
+        assert!(!m.matches("9"));
+    }
+}
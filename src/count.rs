@@ -0,0 +1,540 @@
+//! Counting repetitions instead of unrolling them.
+//!
+//! Deriving `Fan(a, n)` leaves `a' Fan(a, n - 1)`, so every count is a
+//! distinct expression and a [`Matcher`](crate::Matcher) state of its own.
+//! Past [`UNROLL_LIMIT`] repetitions, `Fan` and `Repeat` derive into a `Count`
+//! node instead, in the manner of a counting-set automaton. It holds `a` once,
+//! and pairs each residual of the piece being read with the set of counts that
+//! may still follow it, so `r a^K` for every pair `(r, K)`. Counts that have to
+//! be tracked together, like a piece that may have ended or may still go on,
+//! share one set instead of branching into separate expressions. This holds
+//! whether or not `a` matches the empty string: then `a^k` includes
+//! `a^(k - 1)`, and a residual that may end with counts left over may end the
+//! whole node.
+//!
+//! The counts change with every piece, so an expression with a `Count` in it
+//! cannot be a `Matcher` state as it is. Deriving a `Count` only ever asks
+//! whether a set of counts holds `0`, holds `1` or holds anything larger,
+//! so a `Run` takes every set with a larger count out of the expression and
+//! leaves a [`Placeholder`] with the same answers. The expression that is
+//! left, its skeleton, derives the same way whatever the counts taken out, so
+//! the matcher caches the derivatives of skeletons in a `Cache`, where each
+//! set of counts in a derivative says, by its placeholders, which of the sets
+//! taken out it is made from. Reading a char then only updates the counts, and
+//! no expression is built or interned unless the skeleton changes.
+//!
+//! A `Cache` holds at most [`CACHE_LIMIT`] skeletons and as many derived
+//! skeletons, and starts over once it is full. A pattern that keeps making new
+//! skeletons, which takes counts below `2` in ever new combinations, then
+//! derives and interns an expression on every char, as it would without the
+//! cache.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::charmap::Charmap;
+use crate::charrange::CharRange;
+use crate::intern::ReRef;
+use crate::re::Re;
+
+/// The largest count that `Fan` and `Repeat` still unroll.
+pub(crate) const UNROLL_LIMIT: usize = 16;
+
+/// How many skeletons a `Cache` keeps derivatives of before it starts over.
+pub(crate) const CACHE_LIMIT: usize = 4096;
+
+/// What a skeleton holds instead of the counts of `2` and more of a set taken
+/// out of it.
+#[derive(Clone, Copy, Eq, PartialEq, PartialOrd, Ord, Hash, Debug)]
+pub(crate) enum Placeholder {
+    /// The counts of the `k`-th set taken out.
+    Taken(usize),
+    /// The counts of the `k`-th set taken out, less one.
+    CountedDown(usize),
+}
+
+/// A set of counts, kept as sorted inclusive ranges that neither overlap nor
+/// touch. Only the last range may be unbounded. In a skeleton, the set may
+/// also stand in for counts taken out of it.
+#[derive(Clone, Eq, PartialEq, PartialOrd, Ord, Hash, Debug)]
+pub struct Counts {
+    ranges: Vec<(usize, Option<usize>)>,
+    placeholders: Vec<Placeholder>,
+}
+
+impl Counts {
+    /// The counts from `min` to `max`, or from `min` up when `max` is `None`.
+    pub fn between(min: usize, max: Option<usize>) -> Self {
+        Counts::from_ranges(vec![(min, max)])
+    }
+
+    pub fn exactly(count: usize) -> Self {
+        Counts::between(count, Some(count))
+    }
+
+    /// Normalizes any list of ranges. Empty ranges are dropped.
+    pub fn from_ranges(mut ranges: Vec<(usize, Option<usize>)>) -> Self {
+        ranges.retain(|&(lo, hi)| hi.is_none_or(|hi| lo <= hi));
+        ranges.sort_unstable();
+        let mut merged: Vec<(usize, Option<usize>)> = vec![];
+        for (lo, hi) in ranges {
+            match merged.last_mut() {
+                Some((_, last)) if last.is_none_or(|last| lo <= last + 1) => {
+                    *last = match (*last, hi) {
+                        (Some(last), Some(hi)) => Some(last.max(hi)),
+                        _ => None,
+                    };
+                }
+                _ => merged.push((lo, hi)),
+            }
+        }
+        Counts {
+            ranges: merged,
+            placeholders: vec![],
+        }
+    }
+
+    pub fn ranges(&self) -> impl Iterator<Item = (usize, Option<usize>)> + '_ {
+        self.ranges.iter().cloned()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty() && self.placeholders.is_empty()
+    }
+
+    pub fn contains_zero(&self) -> bool {
+        self.ranges.first().is_some_and(|&(lo, _)| lo == 0)
+    }
+
+    /// Whether the set is exactly `{0}`.
+    fn is_zero(&self) -> bool {
+        self.ranges == [(0, Some(0))] && self.placeholders.is_empty()
+    }
+
+    /// The counts left after one more piece: every non-zero count, less one.
+    ///
+    /// # Panics
+    ///
+    /// If the set stands in for counts that were already counted down, as
+    /// only a skeleton holds placeholders and it is derived once.
+    pub(crate) fn decrement(&self) -> Self {
+        Counts {
+            ranges: self
+                .ranges
+                .iter()
+                .filter(|&&(_, hi)| hi != Some(0))
+                .map(|&(lo, hi)| (lo.saturating_sub(1), hi.map(|hi| hi - 1)))
+                .collect(),
+            placeholders: self
+                .placeholders
+                .iter()
+                .map(|placeholder| match *placeholder {
+                    Placeholder::Taken(k) => Placeholder::CountedDown(k),
+                    Placeholder::CountedDown(_) => {
+                        panic!("a placeholder is counted down more than once")
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    fn union(&self, other: &Counts) -> Self {
+        let mut union = Counts::from_ranges(self.ranges().chain(other.ranges()).collect());
+        union.placeholders = self
+            .placeholders
+            .iter()
+            .chain(&other.placeholders)
+            .cloned()
+            .collect();
+        union.placeholders.sort_unstable();
+        union.placeholders.dedup();
+        union
+    }
+
+    /// The counts below `2`, which is all that deriving looks at besides
+    /// whether there are any others.
+    fn small(&self) -> Self {
+        Counts::from_ranges(
+            self.ranges()
+                .map(|(lo, hi)| (lo, Some(hi.map_or(1, |hi| hi.min(1)))))
+                .collect(),
+        )
+    }
+
+    /// Whether the set holds a count of `2` or more.
+    fn is_large(&self) -> bool {
+        self.ranges
+            .last()
+            .is_some_and(|&(_, hi)| hi.is_none_or(|hi| hi >= 2))
+    }
+
+    /// What a skeleton holds instead of the set, if it is the `k`-th taken
+    /// out.
+    fn placeholder(&self, k: usize) -> Self {
+        let mut counts = self.small();
+        counts.placeholders.push(Placeholder::Taken(k));
+        counts
+    }
+
+    /// The counts with every placeholder replaced by what it stands for.
+    fn fill(&self, taken: &[Counts]) -> Self {
+        self.placeholders.iter().fold(
+            Counts::from_ranges(self.ranges.clone()),
+            |counts, placeholder| match *placeholder {
+                Placeholder::Taken(k) => counts.union(&taken[k]),
+                Placeholder::CountedDown(k) => counts.union(&taken[k].decrement()),
+            },
+        )
+    }
+}
+
+/// The residuals of a `Count` node, each with the counts that may follow it,
+/// sorted by residual.
+#[derive(Clone, Eq, PartialEq, PartialOrd, Ord, Hash, Debug)]
+pub struct Counters(Vec<(ReRef, Counts)>);
+
+impl Counters {
+    pub fn iter(&self) -> std::slice::Iter<'_, (ReRef, Counts)> {
+        self.0.iter()
+    }
+
+    pub(crate) fn nullable(&self, re: &ReRef) -> bool {
+        self.0.iter().any(|(residual, counts)| {
+            residual.nullable() && (counts.contains_zero() || (re.nullable() && !counts.is_empty()))
+        })
+    }
+}
+
+/// Builds `r re^K` for every pair `(r, K)` in `entries`. Pairs with the same
+/// residual are merged, and a residual with nothing left to count is pulled
+/// out of the `Count` node.
+pub(crate) fn count_rc<I: IntoIterator<Item = (ReRef, Counts)>>(re: ReRef, entries: I) -> ReRef {
+    let mut entries: Vec<_> = entries
+        .into_iter()
+        .filter(|(residual, counts)| **residual != Re::Nul && !counts.is_empty())
+        .collect();
+    entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    let mut merged: Vec<(ReRef, Counts)> = vec![];
+    for (residual, counts) in entries {
+        match merged.last_mut() {
+            Some(last) if last.0 == residual => last.1 = last.1.union(&counts),
+            _ => merged.push((residual, counts)),
+        }
+    }
+    let (done, counting): (Vec<_>, Vec<_>) =
+        merged.into_iter().partition(|(_, counts)| counts.is_zero());
+    let mut parts: Vec<ReRef> = done.into_iter().map(|(residual, _)| residual).collect();
+    if !counting.is_empty() {
+        parts.push(ReRef::from(Re::Count(re, Arc::new(Counters(counting)))));
+    }
+    Re::alt(parts)
+}
+
+/// Merges the `Count` operands of an alternation that count the same piece
+/// into one, so that a repetition started again at every char does not leave
+/// one more operand behind each time.
+pub(crate) fn merge_counts(all: &mut Vec<ReRef>) {
+    let mut pieces: Vec<(ReRef, Vec<ReRef>)> = vec![];
+    for x in all.iter() {
+        if let Re::Count(a, _) = &**x {
+            match pieces.iter_mut().find(|(piece, _)| piece == a) {
+                Some((_, nodes)) => nodes.push(x.clone()),
+                None => pieces.push((a.clone(), vec![x.clone()])),
+            }
+        }
+    }
+    for (a, nodes) in pieces.into_iter().filter(|(_, nodes)| nodes.len() > 1) {
+        all.retain(|x| !nodes.contains(x));
+        let entries = nodes.iter().flat_map(|x| match &**x {
+            Re::Count(_, counters) => counters.0.clone(),
+            _ => unreachable!("only counts are merged"),
+        });
+        let merged = count_rc(a, entries.collect::<Vec<_>>());
+        match &*merged {
+            Re::Alt(res) => all.extend(res.iter().cloned()),
+            _ => all.push(merged),
+        }
+    }
+}
+
+/// `re^K`, counted from the start.
+pub(crate) fn counted(re: ReRef, counts: Counts) -> ReRef {
+    count_rc(re, vec![(ReRef::from(Re::Eps), counts)])
+}
+
+pub(crate) fn derive(re: &ReRef, counters: &Counters, ch: &char) -> (CharRange, ReRef) {
+    let mut range = CharRange::all();
+    let mut entries = vec![];
+    let mut started = None;
+    for (residual, counts) in counters.iter() {
+        let (r0, derived) = residual.derive(ch);
+        range = &range & &r0;
+        entries.push((derived, counts.clone()));
+        if residual.nullable() {
+            let rest = counts.decrement();
+            if !rest.is_empty() {
+                let (r1, derived) = started.get_or_insert_with(|| re.derive(ch)).clone();
+                range = &range & &r1;
+                entries.push((derived, rest));
+            }
+        }
+    }
+    (range, count_rc(re.clone(), entries))
+}
+
+/// Rebuilds `re` with every set of counts in it replaced by `f` of it, always
+/// visiting the sets in the same order.
+fn map_counts<F: FnMut(&Counts) -> Counts>(re: &ReRef, f: &mut F) -> ReRef {
+    if !re.has_counters() {
+        return re.clone();
+    }
+    match &**re {
+        Re::Count(a, counters) => {
+            let entries: Vec<_> = counters
+                .iter()
+                .map(|(x, counts)| (map_counts(x, f), f(counts)))
+                .collect();
+            count_rc(a.clone(), entries)
+        }
+        other => other.map_children(|x| map_counts(x, f)),
+    }
+}
+
+/// An expression with counters, as its skeleton and the sets of counts taken
+/// out of it.
+#[derive(Clone, Debug)]
+pub(crate) struct Run {
+    skeleton: ReRef,
+    taken: Arc<[Counts]>,
+}
+
+impl Run {
+    /// Takes the counts of `2` and more out of `re`.
+    pub(crate) fn new(re: ReRef) -> Self {
+        let mut taken = vec![];
+        let skeleton = map_counts(&re, &mut |counts| {
+            if counts.is_large() {
+                taken.push(counts.clone());
+                counts.placeholder(taken.len() - 1)
+            } else {
+                counts.clone()
+            }
+        });
+        Run {
+            skeleton,
+            taken: taken.into(),
+        }
+    }
+
+    /// Whether the expression matches the empty string, which the
+    /// placeholders answer the same as the counts they stand for.
+    pub(crate) fn nullable(&self) -> bool {
+        self.skeleton.nullable()
+    }
+}
+
+/// The derivative of a skeleton, and its sets of counts in the order
+/// `map_counts` visits them.
+#[derive(Debug)]
+struct Step {
+    re: ReRef,
+    sets: Vec<Counts>,
+}
+
+/// The derivatives of the skeletons a matcher has run into.
+#[derive(Debug, Default)]
+pub(crate) struct Cache {
+    steps: HashMap<ReRef, Charmap<Arc<Step>>>,
+    /// The skeleton a derivative turns into, by which of its sets of counts
+    /// are taken out and the counts below `2` of each.
+    skeletons: HashMap<(ReRef, Vec<(Counts, bool)>), ReRef>,
+}
+
+impl Cache {
+    /// Derives `run` by `ch` if everything that takes is cached already.
+    pub(crate) fn get(&self, run: &Run, ch: &char) -> Option<Result<Run, ReRef>> {
+        let step = self.steps.get(&run.skeleton)?.get(ch)?;
+        if !step.re.has_counters() {
+            return Some(Err(step.re.clone()));
+        }
+        let counts = Cache::fill(step, run);
+        let skeleton = self
+            .skeletons
+            .get(&(step.re.clone(), Cache::shapes(&counts)))?;
+        Some(Ok(Cache::run(skeleton.clone(), counts)))
+    }
+
+    /// Derives `run` by `ch`, caching whatever was missing. Gives back the
+    /// expression once it has no counters left.
+    pub(crate) fn step(&mut self, run: &Run, ch: &char) -> Result<Run, ReRef> {
+        if self.steps.len() >= CACHE_LIMIT && !self.steps.contains_key(&run.skeleton) {
+            self.steps.clear();
+        }
+        let steps = self.steps.entry(run.skeleton.clone()).or_default();
+        let step = match steps.get(ch) {
+            Some(step) => step.clone(),
+            None => {
+                let (range, re) = run.skeleton.derive(ch);
+                let mut sets = vec![];
+                map_counts(&re, &mut |counts| {
+                    sets.push(counts.clone());
+                    counts.clone()
+                });
+                let step = Arc::new(Step { re, sets });
+                steps.insert(range, step.clone());
+                step
+            }
+        };
+        if !step.re.has_counters() {
+            return Err(step.re.clone());
+        }
+        let counts = Cache::fill(&step, run);
+        if self.skeletons.len() >= CACHE_LIMIT {
+            self.skeletons.clear();
+        }
+        let skeleton = self
+            .skeletons
+            .entry((step.re.clone(), Cache::shapes(&counts)))
+            .or_insert_with(|| {
+                let (mut i, mut k) = (0, 0);
+                map_counts(&step.re, &mut |_| {
+                    let counts = &counts[i];
+                    i += 1;
+                    if counts.is_large() {
+                        k += 1;
+                        counts.placeholder(k - 1)
+                    } else {
+                        counts.clone()
+                    }
+                })
+            })
+            .clone();
+        Ok(Cache::run(skeleton, counts))
+    }
+
+    /// The sets of counts of the derivative in `step`, with the counts of
+    /// `run` filled in.
+    fn fill(step: &Step, run: &Run) -> Vec<Counts> {
+        step.sets.iter().map(|set| set.fill(&run.taken)).collect()
+    }
+
+    /// What the skeleton of a derivative with these sets of counts depends on.
+    fn shapes(counts: &[Counts]) -> Vec<(Counts, bool)> {
+        counts.iter().map(|c| (c.small(), c.is_large())).collect()
+    }
+
+    fn run(skeleton: ReRef, counts: Vec<Counts>) -> Run {
+        Run {
+            skeleton,
+            taken: counts.into_iter().filter(Counts::is_large).collect(),
+        }
+    }
+
+    /// How many skeletons have derivatives cached.
+    pub(crate) fn len(&self) -> usize {
+        self.steps.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cheese, compile, sundae, toppings, EPS, NUL};
+
+    #[test]
+    fn counts_stay_normalized() {
+        let counts = Counts::from_ranges(vec![(4, Some(7)), (0, Some(1)), (2, Some(3)), (9, None)]);
+        assert_eq!(
+            counts.ranges().collect::<Vec<_>>(),
+            vec![(0, Some(7)), (9, None)]
+        );
+        assert_eq!(
+            counts.decrement().ranges().collect::<Vec<_>>(),
+            vec![(0, Some(6)), (8, None)]
+        );
+        assert!(Counts::between(3, Some(2)).is_empty());
+        assert!(Counts::exactly(0).decrement().is_empty());
+    }
+
+    #[test]
+    fn large_fan_stays_small() {
+        let digit_pair = || cheese('0'..='9') * toppings("ab").fickle();
+        let mut m = compile(sundae("x") * digit_pair().fan(100_000) * sundae("y"));
+        let text = format!("x{}y", "1a2".repeat(50_000));
+        assert!(m.matches(&text));
+        assert!(!m.matches(&text[..text.len() - 2]));
+        assert!(m.matches(&format!("x{}y", "7".repeat(100_000))));
+        assert!(!m.matches(&format!("x{}y", "7".repeat(99_999))));
+        assert!(m.states.len() < 10, "{} states", m.states.len());
+        assert!(m.counting.len() < 10, "{} skeletons", m.counting.len());
+
+        // A repetition without an upper bound that starts again at every char.
+        let mut m = compile(NUL.neg() * cheese('a').at_least(1000));
+        assert!(m.matches(&"a".repeat(5000)));
+        assert!(!m.matches(&format!("{}b{}", "a".repeat(2000), "a".repeat(999))));
+        assert!(m.counting.len() < 10, "{} skeletons", m.counting.len());
+    }
+
+    #[test]
+    fn skeletons_agree_with_deriving() {
+        let derived = |re: &Re, text: &str| {
+            text.chars()
+                .fold(ReRef::from(re.clone()), |re, ch| re.derive(&ch).1)
+                .nullable()
+        };
+        let ab = || cheese('a') | sundae("ab");
+        let res = [
+            ab().fan(20) * cheese('b').repeat(18, Some(20)),
+            ab().at_least(20) & (cheese('a') * cheese('b').fickle()).fan(19),
+            (ab().fan(18) | cheese('a').fan(19)) * cheese('b').star(),
+            (cheese('a').fan(18) * cheese('b')).fan(18),
+            ab().repeat(18, Some(usize::MAX - 1)),
+            NUL.neg() * ab().at_least(18),
+            (cheese('a') | EPS).fan(20) * cheese('b'),
+            cheese('a').fan(20).neg() & ab().repeat(17, None),
+        ];
+        for re in &res {
+            let mut m = compile(re.clone());
+            for text in [
+                "a".repeat(20),
+                "ab".repeat(20),
+                format!("{}{}", "a".repeat(19), "b".repeat(19)),
+                format!("{}b", "a".repeat(18)).repeat(18),
+                format!("{}b", "a".repeat(18)).repeat(17),
+                "ab".repeat(19) + "a",
+            ] {
+                assert_eq!(m.matches(&text), derived(re, &text), "{} on {:?}", re, text);
+            }
+        }
+    }
+
+    #[test]
+    fn agrees_with_unrolling() {
+        let piece = || (sundae("ab") | cheese('b')) * cheese('a').star();
+        let times = |n| (0..n).fold(EPS, |acc, _| acc * piece());
+        let limit = UNROLL_LIMIT + 2;
+        for (counted, unrolled) in [
+            (piece().fan(limit), times(limit)),
+            (
+                piece().repeat(limit - 3, Some(limit)),
+                times(limit - 3) * piece().repeat(0, Some(3)),
+            ),
+            (piece().at_least(limit), times(limit) * piece().star()),
+        ] {
+            let mut counted_m = compile(counted.clone());
+            let mut unrolled_m = compile(unrolled);
+            for n in limit - 4..limit + 3 {
+                for tail in &["", "a", "ab", "ba", "bb"] {
+                    let text = format!("{}{}", "ba".repeat(n), tail);
+                    assert_eq!(
+                        counted_m.matches(&text),
+                        unrolled_m.matches(&text),
+                        "{} on {:?}",
+                        counted,
+                        text
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,326 @@
+//! Combinators defined outside this crate.
+//!
+//! `Moon` and `Consider` are each a bit of state and a rule for deriving it.
+//! [`Derivable`] lets other crates add nodes like them without touching
+//! [`Re`]: a custom node says whether it matches the empty string and how it
+//! derives, and it is compared, hashed and ordered through its own `Eq`,
+//! `Hash` and `Ord` impls, so equal states share one DFA state like any other
+//! expression.
+//!
+//! A custom node is opaque to everything that takes an expression apart. It
+//! never contains capture groups, and [`Re::reverse`],
+//! [`Re::case_insensitive`] and [`Re::to_utf8`] only work if the node provides
+//! its own reverse, case-insensitive form and UTF-8 form: they panic on a
+//! node that does not, rather than leave it as it is and match the wrong
+//! strings. Searching and capturing need reverses too, and fall back to
+//! trying each start in turn when some node has none. `Display` prints
+//! whatever the node's own impl prints, which the parser does not read back.
+
+use std::any::Any;
+use std::cmp::Ordering;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::charrange::CharRange;
+use crate::intern::ReRef;
+use crate::re::Re;
+
+/// A node with its own derivative rule.
+///
+/// `derive` has the same contract as [`Re::derive`]: the returned range must
+/// contain `ch`, and every char in it must give the same derivative.
+pub trait Derivable: Debug + Display + Send + Sync + 'static {
+    fn nullable(&self) -> bool;
+
+    fn derive(&self, ch: &char) -> (CharRange, ReRef);
+
+    /// An expression matching the reverses of the strings this node matches,
+    /// if there is one. `None` by default.
+    fn reverse(&self) -> Option<ReRef> {
+        None
+    }
+
+    /// An expression matching the strings this node matches up to case, if
+    /// there is one. `None` by default.
+    fn case_insensitive(&self) -> Option<ReRef> {
+        None
+    }
+
+    /// The byte-level expression matching exactly the UTF-8 encodings of the
+    /// strings this node matches, if there is one. `None` by default.
+    fn to_utf8(&self) -> Option<ReRef> {
+        None
+    }
+}
+
+/// What a [`CustomNode`] needs on top of [`Derivable`], implemented for
+/// every type that also has `Eq`, `Hash` and `Ord`.
+trait Erased: Derivable {
+    fn as_any(&self) -> &dyn Any;
+    fn dyn_eq(&self, other: &dyn Any) -> bool;
+    fn dyn_cmp(&self, other: &dyn Any) -> Ordering;
+    fn dyn_hash(&self, state: &mut dyn Hasher);
+}
+
+impl<T: Derivable + Eq + Hash + Ord> Erased for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn dyn_eq(&self, other: &dyn Any) -> bool {
+        other.downcast_ref::<T>() == Some(self)
+    }
+
+    fn dyn_cmp(&self, other: &dyn Any) -> Ordering {
+        self.cmp(
+            other
+                .downcast_ref::<T>()
+                .expect("types were compared first"),
+        )
+    }
+
+    fn dyn_hash(&self, mut state: &mut dyn Hasher) {
+        self.hash(&mut state)
+    }
+}
+
+/// A type-erased [`Derivable`]. Nodes of different types are never equal and
+/// are ordered by type first.
+#[derive(Clone)]
+pub struct CustomNode(Arc<dyn Erased>);
+
+impl CustomNode {
+    pub fn new<T: Derivable + Eq + Hash + Ord>(node: T) -> Self {
+        CustomNode(Arc::new(node))
+    }
+
+    /// The node as its concrete type, if it is a `T`.
+    pub fn downcast_ref<T: Derivable>(&self) -> Option<&T> {
+        self.0.as_any().downcast_ref()
+    }
+}
+
+impl Deref for CustomNode {
+    type Target = dyn Derivable;
+
+    fn deref(&self) -> &(dyn Derivable + 'static) {
+        &*self.0
+    }
+}
+
+impl PartialEq for CustomNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.dyn_eq(other.0.as_any())
+    }
+}
+
+impl Eq for CustomNode {}
+
+impl Hash for CustomNode {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_any().type_id().hash(state);
+        self.0.dyn_hash(state);
+    }
+}
+
+impl Ord for CustomNode {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (a, b) = (self.0.as_any(), other.0.as_any());
+        a.type_id()
+            .cmp(&b.type_id())
+            .then_with(|| self.0.dyn_cmp(b))
+    }
+}
+
+impl PartialOrd for CustomNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Debug for CustomNode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&*self.0, f)
+    }
+}
+
+impl Display for CustomNode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Display::fmt(&*self.0, f)
+    }
+}
+
+/// Wraps a custom node into an expression.
+pub fn custom<T: Derivable + Eq + Hash + Ord>(node: T) -> Re {
+    Re::Custom(CustomNode::new(node))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cheese, compile, compile_search, sundae, NUL};
+
+    /// Counts one char modulo some number, like a `Moon` of a single char
+    /// that lets every other char through.
+    #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    struct Tally {
+        ch: char,
+        seen: usize,
+        modulus: usize,
+    }
+
+    impl Display for Tally {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            write!(f, "%tally({}; {}, {})", self.ch, self.seen, self.modulus)
+        }
+    }
+
+    impl Derivable for Tally {
+        fn nullable(&self) -> bool {
+            self.seen == 0
+        }
+
+        fn derive(&self, ch: &char) -> (CharRange, ReRef) {
+            let (range, seen) = if *ch == self.ch {
+                (CharRange::at(*ch), (self.seen + 1) % self.modulus)
+            } else if *ch < self.ch {
+                (CharRange::end_at(self.ch), self.seen)
+            } else {
+                let next = std::char::from_u32(self.ch as u32 + 1).expect("ch > self.ch");
+                (CharRange::start_from(next), self.seen)
+            };
+            (range, ReRef::from(custom(Tally { seen, ..*self })))
+        }
+
+        fn reverse(&self) -> Option<ReRef> {
+            Some(ReRef::from(custom(self.clone())))
+        }
+
+        /// Only a char without case counts the same up to case.
+        fn case_insensitive(&self) -> Option<ReRef> {
+            (!self.ch.is_alphabetic()).then(|| ReRef::from(custom(self.clone())))
+        }
+
+        /// An ASCII char is one byte, which no other char's encoding has.
+        fn to_utf8(&self) -> Option<ReRef> {
+            let valid = cheese(CharRange::all()).star().to_utf8();
+            self.ch
+                .is_ascii()
+                .then(|| Re::and(vec![ReRef::from(valid), ReRef::from(custom(self.clone()))]))
+        }
+    }
+
+    fn tally(ch: char, modulus: usize) -> Re {
+        custom(Tally {
+            ch,
+            seen: 0,
+            modulus,
+        })
+    }
+
+    /// A `Tally` that provides none of the optional hooks.
+    #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    struct Opaque(Tally);
+
+    impl Display for Opaque {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            write!(f, "%opaque({})", self.0)
+        }
+    }
+
+    impl Derivable for Opaque {
+        fn nullable(&self) -> bool {
+            self.0.nullable()
+        }
+
+        fn derive(&self, ch: &char) -> (CharRange, ReRef) {
+            let (range, next) = self.0.derive(ch);
+            let next = match &*next {
+                Re::Custom(node) => node.downcast_ref::<Tally>().cloned(),
+                _ => None,
+            }
+            .expect("a tally derives to a tally");
+            (range, ReRef::from(custom(Opaque(next))))
+        }
+    }
+
+    fn opaque(ch: char, modulus: usize) -> Re {
+        custom(Opaque(Tally {
+            ch,
+            seen: 0,
+            modulus,
+        }))
+    }
+
+    #[test]
+    fn custom_nodes_match() {
+        let mut m = compile(tally('a', 3) & cheese('a'..='c').star());
+        assert!(m.matches(""));
+        assert!(m.matches("abacbca"));
+        assert!(!m.matches("abab"));
+        assert!(!m.matches("aaad"));
+        // One state for each count, plus the dead state.
+        m.matches("aaaaaaaa");
+        assert_eq!(m.states.len(), 4);
+    }
+
+    #[test]
+    fn equality_goes_through_the_node() {
+        assert_eq!(tally('a', 3), tally('a', 3));
+        assert_ne!(tally('a', 3), tally('b', 3));
+        let node = ReRef::from(tally('a', 2));
+        assert_eq!(ReRef::from(tally('a', 2)).id(), node.id());
+        match tally('x', 5) {
+            Re::Custom(node) => {
+                assert_eq!(node.downcast_ref::<Tally>().map(|t| t.modulus), Some(5));
+                assert_eq!(node.to_string(), "%tally(x; 0, 5)");
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn custom_nodes_search() {
+        let mut s = compile_search(sundae("x") * (tally('y', 2) & NUL.neg()) * sundae("x"));
+        assert_eq!(s.find("axyxxyyxb"), Some((3, 8)));
+    }
+
+    #[test]
+    fn search_and_capture_without_a_reverse() {
+        assert_eq!(opaque('y', 2).try_reverse(), None);
+        let mut s = compile_search(sundae("x") * (opaque('y', 2) & NUL.neg()) * sundae("x"));
+        assert_eq!(s.find("axyxxyyxb"), Some((3, 8)));
+        let re = cheese('x').star().capture("xs") * opaque('y', 2);
+        let caps = compile(re).captures("xxyxy").unwrap();
+        assert_eq!(caps.get("xs"), Some((0, 2)));
+    }
+
+    #[test]
+    fn hooks_fold_and_encode() {
+        assert_eq!(tally('1', 2).case_insensitive(), tally('1', 2));
+        let mut m = compile(tally('a', 2).to_utf8());
+        assert!(m.matches_bytes("aéa".as_bytes()));
+        assert!(!m.matches_bytes("aé".as_bytes()));
+        assert!(!m.matches_bytes(b"aa\xff"));
+    }
+
+    #[test]
+    #[should_panic(expected = "has no reverse")]
+    fn reverse_needs_a_hook() {
+        opaque('y', 2).reverse();
+    }
+
+    #[test]
+    #[should_panic(expected = "has no case-insensitive form")]
+    fn case_insensitive_needs_a_hook() {
+        tally('a', 2).case_insensitive();
+    }
+
+    #[test]
+    #[should_panic(expected = "has no UTF-8 form")]
+    fn utf8_needs_a_hook() {
+        tally('é', 2).to_utf8();
+    }
+}
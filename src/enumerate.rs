@@ -0,0 +1,448 @@
+//! Listing the strings an expression matches, shortest first.
+//!
+//! Strings of the same length come out in lexicographic order, so the order
+//! as a whole is shortlex. Every transition out of a state covers a range of
+//! chars that all lead to the same derivative, so the search goes over
+//! [`Pattern`]s, one range per char, and only spells out single strings if
+//! asked for them. Ranges out of one state never overlap, so listing the
+//! patterns of a length in order of their ranges and then the strings of
+//! each pattern in order lists the strings in order too.
+//!
+//! Before following a transition, the search checks that the state it leads
+//! to can still reach a match in exactly the number of chars left, so it
+//! never walks down a prefix that produces nothing. It stops once no state
+//! that can reach a match at all is left at the current length. To know
+//! which states can, the search first explores the states of the expression,
+//! up to [`DEFAULT_BUDGET`] of them, and works backwards from the ones that
+//! match the empty string, one char at a time. A state that was reached but
+//! not explored once the budget ran out is taken to be able to reach a match
+//! in any number of chars, so an expression with that many states may keep
+//! looking for longer strings after its last one.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+
+use crate::charrange::CharRange;
+use crate::explore::{transitions, DEFAULT_BUDGET};
+use crate::intern::ReRef;
+use crate::re::Re;
+
+type Transitions = Arc<[(CharRange, ReRef)]>;
+
+/// A sequence of char ranges, matching every string that has one char from
+/// each range in turn.
+///
+/// `Display` prints it in the text syntax, one class per char, as in
+/// `10[1-3]4`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Pattern(Vec<CharRange>);
+
+impl Pattern {
+    pub fn ranges(&self) -> &[CharRange] {
+        &self.0
+    }
+
+    /// The number of chars in every string the pattern matches.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The strings the pattern matches, in order.
+    pub fn strings(&self) -> PatternStrings {
+        PatternStrings {
+            ranges: self.0.clone(),
+            chars: None,
+            done: false,
+        }
+    }
+
+    /// An expression matching the same strings.
+    pub fn to_re(&self) -> Re {
+        self.0
+            .iter()
+            .rev()
+            .fold(ReRef::from(Re::Eps), |rest, range| {
+                Re::seq(ReRef::from(Re::from(range.clone())), rest)
+            })
+            .as_ref()
+            .clone()
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "ε");
+        }
+        for range in &self.0 {
+            write!(f, "{}", Re::from(range.clone()))?;
+        }
+        Ok(())
+    }
+}
+
+/// The strings of one [`Pattern`], in order.
+#[derive(Clone, Debug)]
+pub struct PatternStrings {
+    ranges: Vec<CharRange>,
+    /// The last string produced, or `None` before the first.
+    chars: Option<Vec<char>>,
+    done: bool,
+}
+
+impl Iterator for PatternStrings {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        if self.done {
+            return None;
+        }
+        match &mut self.chars {
+            None => self.chars = Some(self.ranges.iter().map(CharRange::start).collect()),
+            Some(chars) => {
+                // Count up like an odometer, the last char turning fastest.
+                let mut i = chars.len();
+                loop {
+                    if i == 0 {
+                        self.done = true;
+                        return None;
+                    }
+                    i -= 1;
+                    let range = &self.ranges[i];
+                    if let Some(next) = (chars[i]..range.end()).nth(1) {
+                        chars[i] = next;
+                        break;
+                    }
+                    chars[i] = range.start();
+                }
+            }
+        }
+        self.chars.as_ref().map(|chars| chars.iter().collect())
+    }
+}
+
+/// The states of an expression and which of them lead to a match.
+struct Graph {
+    transitions: HashMap<ReRef, Transitions>,
+    /// The states whose transitions were followed before the budget ran out.
+    explored: HashSet<ReRef>,
+    /// For each explored state, the states with a transition to it.
+    sources: HashMap<ReRef, Vec<ReRef>>,
+    /// The states that were reached but not explored.
+    open: Vec<ReRef>,
+    /// The explored states that can reach a match or an open state.
+    live: HashSet<ReRef>,
+    /// For each number of chars so far, the explored states that reach a
+    /// match or an open state in exactly that many.
+    layers: Vec<HashSet<ReRef>>,
+}
+
+impl Graph {
+    fn new(start: &ReRef) -> Self {
+        let mut graph = Graph {
+            transitions: HashMap::new(),
+            explored: HashSet::new(),
+            sources: HashMap::new(),
+            open: vec![],
+            live: HashSet::new(),
+            layers: vec![],
+        };
+        let mut states = vec![start.clone()];
+        let mut seen: HashSet<ReRef> = states.iter().cloned().collect();
+        let mut i = 0;
+        while i < states.len() {
+            if i == DEFAULT_BUDGET {
+                graph.open = states.split_off(i);
+                break;
+            }
+            let state = states[i].clone();
+            for (_, next) in graph.transitions(&state).iter() {
+                graph
+                    .sources
+                    .entry(next.clone())
+                    .or_default()
+                    .push(state.clone());
+                if seen.insert(next.clone()) {
+                    states.push(next.clone());
+                }
+            }
+            graph.explored.insert(state);
+            i += 1;
+        }
+
+        let ends: HashSet<ReRef> = states
+            .iter()
+            .filter(|re| re.nullable())
+            .chain(&graph.open)
+            .cloned()
+            .collect();
+        let mut pending: Vec<ReRef> = ends.iter().cloned().collect();
+        graph.live = ends.clone();
+        while let Some(re) = pending.pop() {
+            for source in graph.sources.get(&re).into_iter().flatten() {
+                if graph.live.insert(source.clone()) {
+                    pending.push(source.clone());
+                }
+            }
+        }
+        graph.layers.push(ends);
+        graph
+    }
+
+    fn transitions(&mut self, re: &ReRef) -> Transitions {
+        self.transitions
+            .entry(re.clone())
+            .or_insert_with(|| {
+                transitions(re)
+                    .range_values()
+                    .map(|(range, next)| (range.clone(), next.clone()))
+                    .collect()
+            })
+            .clone()
+    }
+
+    /// Whether some string takes `re` to a match, as far as is known.
+    fn live(&self, re: &ReRef) -> bool {
+        !self.explored.contains(re) || self.live.contains(re)
+    }
+
+    /// Whether some string of exactly `len` chars takes `re` to a match, as
+    /// far as is known.
+    fn reaches(&mut self, re: &ReRef, len: usize) -> bool {
+        if len == 0 {
+            return re.nullable();
+        }
+        if !self.explored.contains(re) {
+            return true;
+        }
+        while self.layers.len() <= len {
+            let last = self.layers.last().expect("starts with one layer");
+            let mut layer: HashSet<ReRef> = self.open.iter().cloned().collect();
+            for re in last {
+                for source in self.sources.get(re).into_iter().flatten() {
+                    layer.insert(source.clone());
+                }
+            }
+            self.layers.push(layer);
+        }
+        self.layers[len].contains(re)
+    }
+}
+
+/// Where the search is in the transitions out of a state.
+struct Frame {
+    transitions: Transitions,
+    next: usize,
+}
+
+/// The [`Pattern`]s of an expression's matches, in shortlex order. Made by
+/// [`Re::patterns`].
+pub struct Patterns {
+    graph: Graph,
+    start: ReRef,
+    /// The length of the patterns being listed.
+    len: usize,
+    /// The states that can still reach a match and that some string of `len`
+    /// chars leads to. Empty once the search is over.
+    frontier: Vec<ReRef>,
+    stack: Vec<Frame>,
+    ranges: Vec<CharRange>,
+    started: bool,
+}
+
+impl Patterns {
+    fn new(start: ReRef) -> Self {
+        let graph = Graph::new(&start);
+        let frontier = if graph.live(&start) {
+            vec![start.clone()]
+        } else {
+            vec![]
+        };
+        Patterns {
+            graph,
+            start,
+            len: 0,
+            frontier,
+            stack: vec![],
+            ranges: vec![],
+            started: false,
+        }
+    }
+
+    /// Moves on to the patterns one char longer.
+    fn lengthen(&mut self) {
+        let mut seen = HashSet::new();
+        let mut frontier = vec![];
+        for state in std::mem::take(&mut self.frontier) {
+            for (_, next) in self.graph.transitions(&state).iter() {
+                if seen.insert(next.clone()) && self.graph.live(next) {
+                    frontier.push(next.clone());
+                }
+            }
+        }
+        self.frontier = frontier;
+        self.len += 1;
+        self.started = false;
+    }
+}
+
+impl Iterator for Patterns {
+    type Item = Pattern;
+
+    fn next(&mut self) -> Option<Pattern> {
+        loop {
+            if self.frontier.is_empty() {
+                return None;
+            }
+            if !self.started {
+                self.started = true;
+                if self.graph.reaches(&self.start, self.len) {
+                    if self.len == 0 {
+                        return Some(Pattern(vec![]));
+                    }
+                    let transitions = self.graph.transitions(&self.start);
+                    self.stack.push(Frame {
+                        transitions,
+                        next: 0,
+                    });
+                }
+                continue;
+            }
+            let depth = self.stack.len();
+            let frame = match self.stack.last_mut() {
+                Some(frame) => frame,
+                None => {
+                    self.lengthen();
+                    continue;
+                }
+            };
+            let (range, next) = match frame.transitions.get(frame.next) {
+                Some((range, next)) => (range.clone(), next.clone()),
+                None => {
+                    self.stack.pop();
+                    self.ranges.pop();
+                    continue;
+                }
+            };
+            frame.next += 1;
+            let left = self.len - depth;
+            if !self.graph.reaches(&next, left) {
+                continue;
+            }
+            self.ranges.push(range);
+            if left == 0 {
+                let pattern = Pattern(self.ranges.clone());
+                self.ranges.pop();
+                return Some(pattern);
+            }
+            let transitions = self.graph.transitions(&next);
+            self.stack.push(Frame {
+                transitions,
+                next: 0,
+            });
+        }
+    }
+}
+
+/// The strings an expression matches, in shortlex order. Made by
+/// [`Re::enumerate`].
+pub struct Strings {
+    patterns: Patterns,
+    current: Option<PatternStrings>,
+}
+
+impl Iterator for Strings {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        loop {
+            if let Some(s) = self.current.as_mut().and_then(Iterator::next) {
+                return Some(s);
+            }
+            self.current = Some(self.patterns.next()?.strings());
+        }
+    }
+}
+
+impl Re {
+    /// The strings the expression matches, shortest first and in
+    /// lexicographic order among strings of the same length.
+    pub fn enumerate(&self) -> Strings {
+        Strings {
+            patterns: self.patterns(),
+            current: None,
+        }
+    }
+
+    /// Like [`enumerate`](Self::enumerate), but keeping the chars that lead
+    /// to the same state together, so that each [`Pattern`] stands for all
+    /// the strings it spells.
+    pub fn patterns(&self) -> Patterns {
+        Patterns::new(ReRef::from(self.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::strings;
+    use crate::{cheese, compile, consider, sundae, NUL};
+
+    #[test]
+    fn shortlex_order() {
+        let ab = cheese('a') | cheese('b');
+        let first: Vec<_> = ab.star().enumerate().take(7).collect();
+        assert_eq!(first, vec!["", "a", "b", "aa", "ab", "ba", "bb"]);
+
+        // Every string over the letters it uses, checked by a matcher.
+        let re = (cheese('a') | sundae("bc")).star() & (NUL.neg() * sundae("aa") * NUL.neg()).neg();
+        let mut m = compile(re.clone());
+        let expected: Vec<_> = strings("abc", 6)
+            .into_iter()
+            .filter(|s| m.matches(s))
+            .collect();
+        let listed: Vec<_> = re.enumerate().take_while(|s| s.len() <= 6).collect();
+        assert_eq!(listed, expected);
+    }
+
+    #[test]
+    fn finite_languages_end() {
+        let decimal = || ('0'..='9').map(cheese).collect::<Vec<_>>();
+        let quarters = cheese('0'..='9').fan(2) & consider(decimal(), 0, 25);
+        let listed: Vec<_> = quarters.enumerate().collect();
+        assert_eq!(listed, vec!["00", "25", "50", "75"]);
+
+        // After `x` nothing can match, though the state is not `NUL`.
+        let a = || cheese('a').star();
+        let re = sundae("ab") | (cheese('x') * (a() & a().neg()));
+        assert_eq!(re.enumerate().collect::<Vec<_>>(), vec!["ab"]);
+        assert_eq!(NUL.enumerate().next(), None);
+    }
+
+    #[test]
+    fn long_strings_come_quickly() {
+        let mut strings = cheese('a').fan(20_000).enumerate();
+        assert_eq!(strings.next(), Some("a".repeat(20_000)));
+        assert_eq!(strings.next(), None);
+    }
+
+    #[test]
+    fn patterns_keep_ranges() {
+        let re = (sundae("10") * cheese('1'..='3') * cheese('4'))
+            | (sundae("10") * cheese('7'..='9').star());
+        let patterns: Vec<_> = re.patterns().take(4).map(|p| p.to_string()).collect();
+        assert_eq!(patterns, vec!["10", "10[7-9]", "10[1-3]4", "10[7-9][7-9]"]);
+        let pattern = re.patterns().nth(2).unwrap();
+        assert_eq!(
+            pattern.strings().collect::<Vec<_>>(),
+            vec!["1014", "1024", "1034"]
+        );
+        assert_eq!(Re::parse(&pattern.to_string()), Ok(pattern.to_re()));
+        assert_eq!(Pattern(vec![]).strings().collect::<Vec<_>>(), vec![""]);
+    }
+}
@@ -0,0 +1,258 @@
+//! Whether two expressions match the same strings.
+//!
+//! Two expressions match the same strings exactly when they agree on the
+//! empty string and, for every char, their derivatives match the same strings
+//! again. Following pairs of derivatives from the starting pair and checking
+//! that each pair agrees on the empty string is a bisimulation, in the manner
+//! of Hopcroft and Karp: pairs are merged into classes with a union-find, so
+//! a pair whose sides are already known to be equivalent is not followed a
+//! second time. If some pair disagrees, the chars leading to it make a string
+//! that one side matches and the other does not.
+//!
+//! Inclusion is equivalence in disguise: `a` matches no more than `b` exactly
+//! when `a | b` matches the same strings as `b`.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::charrange::CharRange;
+use crate::explore::{each_range, BudgetExceeded, DEFAULT_BUDGET};
+use crate::intern::ReRef;
+use crate::re::Re;
+
+/// The outcome of comparing what two expressions match.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// The comparison holds for every string.
+    Holds,
+    /// A string on which the comparison fails.
+    Counterexample(String),
+}
+
+impl Verdict {
+    pub fn holds(&self) -> bool {
+        *self == Verdict::Holds
+    }
+
+    pub fn counterexample(&self) -> Option<&str> {
+        match self {
+            Verdict::Holds => None,
+            Verdict::Counterexample(s) => Some(s),
+        }
+    }
+}
+
+/// Classes of expressions known to match the same strings.
+#[derive(Default)]
+struct UnionFind {
+    parent: HashMap<ReRef, ReRef>,
+}
+
+impl UnionFind {
+    fn find(&mut self, re: &ReRef) -> ReRef {
+        let parent = match self.parent.get(re) {
+            Some(parent) => parent.clone(),
+            None => return re.clone(),
+        };
+        let root = self.find(&parent);
+        self.parent.insert(re.clone(), root.clone());
+        root
+    }
+
+    /// Merges the classes of `a` and `b`, returning false if they were
+    /// merged already.
+    fn union(&mut self, a: &ReRef, b: &ReRef) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        self.parent.insert(a, b);
+        true
+    }
+}
+
+/// A pair of derivatives, with the pair it was derived from and by which
+/// char.
+struct Pair {
+    left: ReRef,
+    right: ReRef,
+    parent: Option<(usize, char)>,
+}
+
+fn bisimulate(left: ReRef, right: ReRef, budget: usize) -> Result<Verdict, BudgetExceeded> {
+    let mut classes = UnionFind::default();
+    let mut pairs = vec![Pair {
+        left,
+        right,
+        parent: None,
+    }];
+    let mut queue = VecDeque::from(vec![0]);
+    while let Some(i) = queue.pop_front() {
+        let (left, right) = (pairs[i].left.clone(), pairs[i].right.clone());
+        if !classes.union(&left, &right) {
+            continue;
+        }
+        if left.nullable() != right.nullable() {
+            let mut path = vec![];
+            let mut at = i;
+            while let Some((parent, ch)) = pairs[at].parent {
+                path.push(ch);
+                at = parent;
+            }
+            return Ok(Verdict::Counterexample(path.into_iter().rev().collect()));
+        }
+        each_range(|ch| {
+            let (r0, l) = left.derive(&ch);
+            let (r1, r) = right.derive(&ch);
+            let range: CharRange = &r0 & &r1;
+            if l != r {
+                if pairs.len() >= budget {
+                    return Err(BudgetExceeded::new(budget));
+                }
+                queue.push_back(pairs.len());
+                pairs.push(Pair {
+                    left: l,
+                    right: r,
+                    parent: Some((i, range.start())),
+                });
+            }
+            Ok(range)
+        })?;
+    }
+    Ok(Verdict::Holds)
+}
+
+impl Re {
+    /// Whether `a` and `b` match the same strings, or a string that only one
+    /// of them matches. Gives up after comparing [`DEFAULT_BUDGET`] pairs of
+    /// states.
+    pub fn equivalent(a: &Re, b: &Re) -> Result<Verdict, BudgetExceeded> {
+        Self::equivalent_within(a, b, DEFAULT_BUDGET)
+    }
+
+    /// Like [`equivalent`](Self::equivalent), but giving up after comparing
+    /// `budget` pairs of states instead.
+    pub fn equivalent_within(a: &Re, b: &Re, budget: usize) -> Result<Verdict, BudgetExceeded> {
+        bisimulate(ReRef::from(a.clone()), ReRef::from(b.clone()), budget)
+    }
+
+    /// Whether every string that `a` matches is matched by `b`, or a string
+    /// that `a` matches and `b` does not. Gives up after comparing
+    /// [`DEFAULT_BUDGET`] pairs of states.
+    pub fn subset_of(a: &Re, b: &Re) -> Result<Verdict, BudgetExceeded> {
+        Self::subset_of_within(a, b, DEFAULT_BUDGET)
+    }
+
+    /// Like [`subset_of`](Self::subset_of), but giving up after comparing
+    /// `budget` pairs of states instead.
+    pub fn subset_of_within(a: &Re, b: &Re, budget: usize) -> Result<Verdict, BudgetExceeded> {
+        let b = ReRef::from(b.clone());
+        let either = Re::alt(vec![ReRef::from(a.clone()), b.clone()]);
+        bisimulate(either, b, budget)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cheese, compile, consider, digit_sum, sundae, toppings, EPS, NUL};
+
+    fn counterexample(verdict: Result<Verdict, BudgetExceeded>) -> Option<String> {
+        verdict.unwrap().counterexample().map(String::from)
+    }
+
+    #[test]
+    fn rewrites_are_equivalent() {
+        let a = || cheese('a');
+        assert!(Re::equivalent(&(a() * a().star()), &(a().star() * a()))
+            .unwrap()
+            .holds());
+        assert!(Re::equivalent(
+            &(a() | sundae("bc")).star(),
+            &(a().star() * sundae("bc").star()).star()
+        )
+        .unwrap()
+        .holds());
+        assert!(Re::equivalent(
+            &(a().star() & a().fan(2).star().neg()),
+            &(a() * a().fan(2).star())
+        )
+        .unwrap()
+        .holds());
+        assert!(
+            Re::equivalent(&NUL.neg(), &(EPS | cheese(CharRange::all()).star()))
+                .unwrap()
+                .holds()
+        );
+        assert_eq!(
+            counterexample(Re::equivalent(&a().star(), &a().fan(2).star())),
+            Some("a".to_string())
+        );
+        assert_eq!(
+            counterexample(Re::equivalent(&sundae("abc"), &sundae("abd"))).map(|s| s.len()),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn counters_and_moons() {
+        // A number and the sum of its digits leave the same remainder mod 3.
+        let decimal = || ('0'..='9').map(cheese).collect::<Vec<_>>();
+        assert!(
+            Re::equivalent(&consider(decimal(), 1, 3), &digit_sum(decimal(), 1, 3))
+                .unwrap()
+                .holds()
+        );
+        let wrong = counterexample(Re::equivalent(
+            &consider(decimal(), 1, 4),
+            &digit_sum(decimal(), 1, 4),
+        ))
+        .unwrap();
+        assert_ne!(
+            compile(consider(decimal(), 1, 4)).matches(&wrong),
+            compile(digit_sum(decimal(), 1, 4)).matches(&wrong)
+        );
+
+        let moons = (cheese('0'..='9').moon_phase(1, 3) * cheese('a'..='f').moon_phase(2, 3))
+            .moon_phase(0, 2);
+        let counted = (cheese('0'..='9').repeat_mod(2, 3, 2)
+            * cheese('a'..='f').repeat_mod(1, 3, 1))
+        .repeat_mod(0, 2, 2);
+        assert!(Re::equivalent(&moons, &counted).unwrap().holds());
+        assert!(Re::equivalent(
+            &cheese('a').moon_phase(10, 3),
+            &cheese('a').repeat_mod(2, 3, 5)
+        )
+        .unwrap()
+        .holds());
+    }
+
+    #[test]
+    fn inclusion() {
+        let digits = || vec![toppings("cdb"), sundae("cdb")];
+        let cdb = || toppings("cdb").star();
+        assert!(Re::subset_of(&consider(digits(), 1, 3), &cdb())
+            .unwrap()
+            .holds());
+        assert_eq!(
+            counterexample(Re::subset_of(&cdb(), &consider(digits(), 1, 3))),
+            Some(String::new())
+        );
+        assert!(Re::subset_of(&NUL, &sundae("x")).unwrap().holds());
+        assert_eq!(
+            counterexample(Re::subset_of(
+                &cheese('a').star(),
+                &cheese('a').repeat(1, None)
+            )),
+            Some(String::new())
+        );
+    }
+
+    #[test]
+    fn gives_up_on_budget() {
+        let a = cheese('a');
+        assert_eq!(
+            Re::equivalent_within(&a.clone().fan(40), &a.fan(41), 10),
+            Err(BudgetExceeded::new(10))
+        );
+    }
+}
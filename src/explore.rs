@@ -0,0 +1,169 @@
+//! Searching the states an expression derives into.
+//!
+//! Every char in a range returned by [`Re::derive`] gives the same
+//! derivative, so the derivatives of a state by all chars fit in a
+//! [`Charmap`] with one entry per range. Following those ranges from the
+//! starting expression visits the same states a [`Matcher`](crate::Matcher)
+//! would build, without reading any input. With `Neg`, `And` or a `Count` in
+//! the expression there may be a great many of them, so every search here
+//! takes a budget of states and gives up once it is spent.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use crate::charmap::Charmap;
+use crate::charrange::CharRange;
+use crate::intern::ReRef;
+use crate::re::Re;
+
+/// How many states a search visits by default before giving up.
+pub const DEFAULT_BUDGET: usize = 100_000;
+
+/// A search visited more states than its budget allowed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BudgetExceeded {
+    states: usize,
+}
+
+impl BudgetExceeded {
+    pub(crate) fn new(states: usize) -> Self {
+        BudgetExceeded { states }
+    }
+
+    /// The budget that ran out.
+    pub fn states(&self) -> usize {
+        self.states
+    }
+}
+
+impl Display for BudgetExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "gave up after visiting {} states", self.states)
+    }
+}
+
+impl Error for BudgetExceeded {}
+
+/// The derivatives of `re` by every char, one entry per range of chars that
+/// share a derivative. Ranges that lead to `NUL` are left out.
+pub(crate) fn transitions(re: &ReRef) -> Charmap<ReRef> {
+    let mut next = Charmap::new();
+    let walked: Result<(), Infallible> = each_range(|ch| {
+        let (range, derived) = re.derive(&ch);
+        if *derived != Re::Nul {
+            next.insert(range.clone(), derived);
+        }
+        Ok(range)
+    });
+    walked.unwrap_or_else(|never| match never {});
+    next
+}
+
+/// Calls `visit` with the first char of every range of chars, each range
+/// being the one `visit` returns for its first char, until every char is
+/// covered or `visit` fails.
+pub(crate) fn each_range<E>(mut visit: impl FnMut(char) -> Result<CharRange, E>) -> Result<(), E> {
+    let mut ch = '\0';
+    while ch < std::char::MAX {
+        ch = visit(ch)?.end();
+    }
+    Ok(())
+}
+
+/// Breadth first from `start`, the shortest string leading to a state that
+/// matches the empty string. Each step takes the first char of its range.
+fn shortest(start: ReRef, budget: usize) -> Result<Option<String>, BudgetExceeded> {
+    // The state each state was first reached from, and by which char.
+    let mut parents: HashMap<ReRef, Option<(ReRef, char)>> = HashMap::new();
+    let mut queue = VecDeque::new();
+    parents.insert(start.clone(), None);
+    queue.push_back(start);
+    while let Some(re) = queue.pop_front() {
+        if re.nullable() {
+            let mut path = vec![];
+            let mut at = &re;
+            while let Some((parent, ch)) = &parents[at] {
+                path.push(*ch);
+                at = parent;
+            }
+            return Ok(Some(path.into_iter().rev().collect()));
+        }
+        for (range, next) in transitions(&re).range_values() {
+            if parents.contains_key(next) {
+                continue;
+            }
+            if parents.len() >= budget {
+                return Err(BudgetExceeded::new(budget));
+            }
+            parents.insert(next.clone(), Some((re.clone(), range.start())));
+            queue.push_back(next.clone());
+        }
+    }
+    Ok(None)
+}
+
+impl Re {
+    /// Whether the expression matches no string at all, searching at most
+    /// [`DEFAULT_BUDGET`] states.
+    pub fn is_empty(&self) -> Result<bool, BudgetExceeded> {
+        self.witness().map(|w| w.is_none())
+    }
+
+    /// One of the shortest strings the expression matches, or `None` if it
+    /// matches nothing, searching at most [`DEFAULT_BUDGET`] states.
+    pub fn witness(&self) -> Result<Option<String>, BudgetExceeded> {
+        self.witness_within(DEFAULT_BUDGET)
+    }
+
+    /// Like [`witness`](Self::witness), but giving up after visiting `budget`
+    /// states instead.
+    pub fn witness_within(&self, budget: usize) -> Result<Option<String>, BudgetExceeded> {
+        shortest(ReRef::from(self.clone()), budget)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cheese, consider, sundae, EPS, NUL};
+
+    #[test]
+    fn finds_shortest_witnesses() {
+        assert_eq!(NUL.witness(), Ok(None));
+        assert_eq!(EPS.witness(), Ok(Some(String::new())));
+        assert_eq!((cheese('a') & cheese('b')).is_empty(), Ok(true));
+        assert_eq!(
+            (cheese('a'..='z').star() & sundae("ab").star().neg() & cheese('a').star().neg())
+                .witness(),
+            Ok(Some("b".to_string()))
+        );
+        let re = sundae("xyz") | cheese('a'..='c').fan(2);
+        assert_eq!(re.witness(), Ok(Some("aa".to_string())));
+        assert_eq!(
+            (cheese('a').repeat(3, None) & cheese('a').fan(3).neg()).witness(),
+            Ok(Some("aaaa".to_string()))
+        );
+    }
+
+    #[test]
+    fn confirms_single_answers() {
+        // Like the insurance number of src/bin/dr.rs, a fixed length and a
+        // value pin down one string, and the other checks must agree with it.
+        let decimal = || ('0'..='9').map(cheese).collect::<Vec<_>>();
+        let re = cheese('0'..='9').fan(3)
+            & consider(decimal(), 337, 1000)
+            & (NUL.neg() * sundae("33") * NUL.neg());
+        assert_eq!(re.witness(), Ok(Some("337".to_string())));
+        assert_eq!((re.clone() & sundae("337").neg()).is_empty(), Ok(true));
+        assert_eq!((re & consider(decimal(), 0, 2)).is_empty(), Ok(true));
+    }
+
+    #[test]
+    fn gives_up_on_budget() {
+        let re = cheese('a').fan(50) & cheese('b').star().neg();
+        assert_eq!(re.witness_within(10), Err(BudgetExceeded::new(10)));
+        assert_eq!(re.witness_within(100).map(|w| w.is_some()), Ok(true));
+    }
+}
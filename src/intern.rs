@@ -0,0 +1,232 @@
+//! Hash-consing for [`Re`].
+//!
+//! Every `Re` that ends up inside another one, or inside a `Matcher`, goes
+//! through a process-wide arena that hands out one [`ReRef`] per structurally
+//! distinct expression. Since children are interned before their parents,
+//! structural equality of two nodes is the same as equality of their ids, so
+//! comparing or hashing a `ReRef` never looks past the id. Ordering does, as
+//! ids depend on what happened to be interned first and the canonical order
+//! of `Alt` and `And` operands must not.
+//!
+//! The arena is split into shards by hash, each behind its own lock, so
+//! threads deriving unrelated expressions rarely wait on each other. The
+//! arena only holds weak references, so a node is freed once the last
+//! `ReRef` to it goes, and its entry is swept from the arena as it is.
+
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+use crate::re::Re;
+
+struct Node {
+    id: usize,
+    hash: u64,
+    nullable: bool,
+    groups: bool,
+    counters: bool,
+    re: Re,
+}
+
+/// A shared handle to an interned [`Re`].
+#[derive(Clone)]
+pub struct ReRef(Arc<Node>);
+
+const SHARDS: usize = 16;
+
+/// The nodes of a shard, by the hash of their expression.
+type Shard = Mutex<HashMap<u64, Vec<Weak<Node>>>>;
+
+static ARENA: OnceLock<Vec<Shard>> = OnceLock::new();
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn hash(re: &Re) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    re.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn shard(hash: u64) -> &'static Shard {
+    let shards = ARENA.get_or_init(|| (0..SHARDS).map(|_| Mutex::default()).collect());
+    &shards[hash as usize % SHARDS]
+}
+
+impl ReRef {
+    /// Interns `re`, returning the existing handle if an equal expression is
+    /// still interned.
+    pub fn new(re: Re) -> Self {
+        let hash = hash(&re);
+        // Handles upgraded on the way are only dropped once the lock is
+        // released, as dropping the last one takes the lock again.
+        let mut others = vec![];
+        let mut shard = shard(hash).lock().expect("arena lock poisoned");
+        let bucket = shard.entry(hash).or_default();
+        for node in bucket.iter().filter_map(Weak::upgrade) {
+            if node.re == re {
+                drop(shard);
+                return ReRef(node);
+            }
+            others.push(node);
+        }
+        let node = Arc::new(Node {
+            id: NEXT_ID.fetch_add(1, Relaxed),
+            hash,
+            nullable: re.nullable(),
+            groups: re.has_groups(),
+            counters: re.has_counters(),
+            re,
+        });
+        bucket.push(Arc::downgrade(&node));
+        drop(shard);
+        ReRef(node)
+    }
+
+    /// An identifier, unique among the nodes interned in this process. An
+    /// expression that is freed and interned again gets a new one.
+    pub fn id(&self) -> usize {
+        self.0.id
+    }
+
+    /// Whether the expression matches the empty string, computed once when
+    /// the node was interned.
+    pub fn nullable(&self) -> bool {
+        self.0.nullable
+    }
+
+    /// Whether a capture group occurs in the expression, also computed once.
+    pub fn has_groups(&self) -> bool {
+        self.0.groups
+    }
+
+    /// Whether a `Count` occurs in the expression, also computed once.
+    pub fn has_counters(&self) -> bool {
+        self.0.counters
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let mut shard = shard(self.hash).lock().expect("arena lock poisoned");
+        if let Some(bucket) = shard.get_mut(&self.hash) {
+            bucket.retain(|node| node.strong_count() > 0);
+            if bucket.is_empty() {
+                shard.remove(&self.hash);
+            }
+        }
+    }
+}
+
+impl Deref for ReRef {
+    type Target = Re;
+
+    fn deref(&self) -> &Re {
+        &self.0.re
+    }
+}
+
+impl AsRef<Re> for ReRef {
+    fn as_ref(&self) -> &Re {
+        &self.0.re
+    }
+}
+
+impl From<Re> for ReRef {
+    fn from(re: Re) -> Self {
+        ReRef::new(re)
+    }
+}
+
+impl PartialEq for ReRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.id == other.0.id
+    }
+}
+
+impl Eq for ReRef {}
+
+impl Hash for ReRef {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.id.hash(state)
+    }
+}
+
+/// Orders structurally, like the expressions themselves, so the order does
+/// not depend on interning order. Equal ids skip the walk.
+impl Ord for ReRef {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self.0.id == other.0.id {
+            return Ordering::Equal;
+        }
+        self.0.re.cmp(&other.0.re)
+    }
+}
+
+impl PartialOrd for ReRef {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Debug for ReRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&self.0.re, f)
+    }
+}
+
+impl Display for ReRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.0.re, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cheese, sundae};
+
+    #[test]
+    fn equal_expressions_share_a_node() {
+        let a = ReRef::from(cheese('a').star() * sundae("bc"));
+        let b = ReRef::from(cheese('a').star() * sundae("bc"));
+        assert_eq!(a.id(), b.id());
+        assert!(Arc::ptr_eq(&a.0, &b.0));
+
+        let c = ReRef::from(cheese('a').star() * sundae("bd"));
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn order_ignores_interning_order() {
+        let late = ReRef::from(sundae("zz-interned-first"));
+        let early = ReRef::from(sundae("aa-interned-second"));
+        assert!(late.id() < early.id());
+        assert!(early < late);
+        assert_eq!(
+            Re::alt(vec![late, early]).to_string(),
+            "\"aa-interned-second\"|\"zz-interned-first\""
+        );
+    }
+
+    #[test]
+    fn unused_nodes_are_freed() {
+        let re = || sundae("freed-once-unused").star();
+        let node = ReRef::from(re());
+        let weak = Arc::downgrade(&node.0);
+        let id = node.id();
+        assert_eq!(ReRef::from(re()).id(), id);
+        drop(node);
+        assert!(weak.upgrade().is_none());
+        assert_ne!(ReRef::from(re()).id(), id);
+    }
+
+    #[test]
+    fn nullable_is_cached() {
+        assert!(ReRef::from(cheese('a').star()).nullable());
+        assert!(!ReRef::from(cheese('a').star() * cheese('b')).nullable());
+    }
+}
@@ -1,19 +1,53 @@
 #![allow(dead_code)]
 
 use std::collections::HashMap;
-use std::rc::Rc;
 
+pub mod bytes;
+pub mod captures;
+pub mod case;
 pub mod charmap;
 pub mod charrange;
+pub mod checksum;
+pub mod count;
+pub mod custom;
+pub mod enumerate;
+pub mod equiv;
+pub mod explore;
+pub mod intern;
+pub mod parse;
+pub mod parses;
 pub mod re;
+pub mod residue;
+pub mod reverse;
+pub mod search;
+pub mod shared;
+pub mod size;
+#[cfg(test)]
+mod testing;
 
+pub use bytes::{any_byte, byte_lit, byte_set};
+pub use captures::Captures;
 use charmap::Charmap;
-pub use re::{cheese, consider, sundae, toppings, Re, EPS, NUL};
+pub use checksum::{digit_sum, luhn, weighted_sum};
+pub use custom::{custom, CustomNode, Derivable};
+pub use enumerate::{Pattern, Patterns, Strings};
+pub use equiv::Verdict;
+pub use explore::BudgetExceeded;
+pub use intern::ReRef;
+pub use parse::ParseError;
+pub use parses::{ambiguity, ambiguity_with, consider_lsd_parses, consider_parses, Parses};
+pub use re::{
+    cheese, consider, consider_any, consider_lsd, consider_lsd_any, lookahead, lookbehind,
+    negative_lookahead, negative_lookbehind, sundae, toppings, LitStr, Lookaround, Re, EPS, NUL,
+};
+pub use search::{compile_search, Searcher};
+pub use shared::{compile_shared, SharedMatcher};
+pub use size::{Natural, Size};
 
 #[derive(Debug)]
 struct StateImpl {
-    re: Rc<Re>,
-    next: Charmap<State>,
+    re: ReRef,
+    next: Charmap<Cursor>,
     nullable: bool,
 }
 
@@ -24,75 +58,136 @@ impl State {
     const INITIAL: Self = State(0);
 }
 
+/// Where a run of a matcher is. Expressions with a `Count` in them are not
+/// cached as states, since every count would make a new one, so a run holds
+/// on to the counts apart from the rest of the expression (see
+/// `count::Run`).
+#[derive(Clone, Debug)]
+enum Cursor<S = State> {
+    At(S),
+    Counting(count::Run),
+}
+
+impl Cursor {
+    const START: Self = Cursor::At(State::INITIAL);
+}
+
 #[derive(Debug)]
 pub struct Matcher {
     states: Vec<StateImpl>,
-    res: HashMap<Rc<Re>, State>,
+    res: HashMap<ReRef, State>,
+    counting: count::Cache,
+    submatchers: captures::Submatchers,
 }
 
-impl From<Rc<Re>> for StateImpl {
-    fn from(re: Rc<Re>) -> Self {
+impl From<ReRef> for StateImpl {
+    fn from(re: ReRef) -> Self {
         let nullable = re.nullable();
         StateImpl {
-            re: re,
+            re,
             next: Charmap::new(),
-            nullable: nullable,
+            nullable,
         }
     }
 }
 
 impl Matcher {
-    pub fn new(re: Rc<Re>) -> Self {
+    pub fn new(re: ReRef) -> Self {
         Matcher {
             states: vec![re.clone().into()],
             res: vec![(re, State::INITIAL)].into_iter().collect(),
+            counting: Default::default(),
+            submatchers: Default::default(),
         }
     }
 
-    fn add_state(&mut self, re: Rc<Re>) -> State {
+    fn add_state(&mut self, re: ReRef) -> State {
         if let Some(state) = self.res.get(&re) {
-            return *state;
+            *state
         } else {
             let imp = StateImpl::from(re);
             let state = State(self.states.len());
-            self.res.insert(imp.re.clone(), state.clone());
+            self.res.insert(imp.re.clone(), state);
             self.states.push(imp);
             state
         }
     }
 
-    fn step(&mut self, state: State, ch: &char) -> State {
+    fn cursor(&mut self, re: ReRef) -> Cursor {
+        if !re.has_counters() {
+            Cursor::At(self.add_state(re))
+        } else {
+            Cursor::Counting(count::Run::new(re))
+        }
+    }
+
+    fn step(&mut self, cursor: &Cursor, ch: &char) -> Cursor {
         assert!(*ch < std::char::MAX);
+        let state = match cursor {
+            Cursor::At(state) => *state,
+            Cursor::Counting(run) => {
+                return match self.counting.step(run, ch) {
+                    Ok(run) => Cursor::Counting(run),
+                    Err(next_re) => self.cursor(next_re),
+                };
+            }
+        };
         let imp = &mut self.states[state.0];
         if let Some(next) = imp.next.get(ch) {
-            return *next;
+            return next.clone();
         }
         let (range, next_re) = imp.re.derive(ch);
-        println!("{:?} {:?}", range, next_re);
-
-        let next = self.add_state(next_re.into());
+        let next = self.cursor(next_re);
         let imp = &mut self.states[state.0];
         let inserted = imp.next.try_insert(range, next.clone());
         assert!(inserted);
-        return next;
+        next
+    }
+
+    /// Whether nothing read from here on can lead to a match. An expression
+    /// with counters in it is never `NUL`.
+    fn is_dead(&self, cursor: &Cursor) -> bool {
+        match cursor {
+            Cursor::At(state) => *self.states[state.0].re == Re::Nul,
+            Cursor::Counting(_) => false,
+        }
+    }
+
+    fn nullable(&self, cursor: &Cursor) -> bool {
+        match cursor {
+            Cursor::At(state) => self.states[state.0].nullable,
+            Cursor::Counting(run) => run.nullable(),
+        }
     }
 
     pub fn matches(&mut self, s: &str) -> bool {
-        let mut state = State::INITIAL;
+        let mut cursor = Cursor::START;
         for c in s.chars() {
-            state = self.step(state, &c)
+            cursor = self.step(&cursor, &c)
+        }
+        self.nullable(&cursor)
+    }
+
+    /// Matches raw bytes, each one read as the char with the same value. See
+    /// [`bytes`].
+    pub fn matches_bytes(&mut self, bytes: &[u8]) -> bool {
+        let mut cursor = Cursor::START;
+        for &b in bytes {
+            cursor = self.step(&cursor, &char::from(b))
         }
-        self.states[state.0].nullable
+        self.nullable(&cursor)
     }
 }
 
-pub fn compile<T: Into<Rc<Re>>>(re: T) -> Matcher {
+pub fn compile<T: Into<ReRef>>(re: T) -> Matcher {
     Matcher::new(re.into())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::explore::each_range;
+    use std::convert::Infallible;
 
     #[test]
     fn eps_matches_empty_string() {
@@ -129,6 +224,37 @@ mod test {
         }
     }
 
+    #[test]
+    fn consider_large_moduli_and_target_sets() {
+        let hex = || ('0'..='9').chain('a'..='f').map(cheese).collect::<Vec<_>>();
+        let mut m = compile(consider(hex(), 7777, 313337) & EPS.neg());
+        assert!(m.matches(&format!("{:x}", 7777)));
+        assert!(m.matches(&format!("{:x}", 313337 * 41 + 7777)));
+        assert!(!m.matches(&format!("{:x}", 313337 * 41 + 7778)));
+
+        // The largest prime below 2^128, read in hex.
+        let p = u128::MAX - 158;
+        let mut m = compile(consider_any(hex(), vec![5, p - 1], p) & EPS.neg());
+        assert!(m.matches("5"));
+        assert!(m.matches(&format!("{:x}", p - 1)));
+        // 2^128 is 159 modulo p, so this is p - 1 as well.
+        assert!(m.matches(&format!("1{:032x}", p - 160)));
+        assert!(!m.matches(&format!("{:x}", p)));
+
+        let mut m = compile(consider_lsd_any(hex(), vec![5, p - 1], p) & EPS.neg());
+        let reversed: String = format!("{:x}", p - 1).chars().rev().collect();
+        assert!(m.matches(&reversed));
+        assert!(m.matches("50"));
+        assert!(!m.matches("05"));
+
+        let decimal = || ('0'..='9').map(cheese).collect::<Vec<_>>();
+        let mut m = compile(consider_any(decimal(), vec![1, 4], 7));
+        for n in 0..100 {
+            assert_eq!(m.matches(&n.to_string()), n % 7 == 1 || n % 7 == 4);
+        }
+        assert_eq!(consider_any(decimal(), vec![7, 9], 7), NUL);
+    }
+
     #[test]
     fn test_neg_nul() {
         let mut m = compile(NUL.neg());
@@ -136,4 +262,44 @@ mod test {
             assert!(m.matches(s));
         }
     }
+    /// Explores every state reachable from the initial one and returns how
+    /// many there are.
+    fn expand_all(m: &mut Matcher) -> usize {
+        let mut i = 0;
+        while i < m.states.len() {
+            let walked: Result<(), Infallible> = each_range(|ch| {
+                m.step(&Cursor::At(State(i)), &ch);
+                Ok(m.states[i].next.get_entry(&ch).unwrap().0.clone())
+            });
+            walked.unwrap();
+            i += 1;
+        }
+        m.states.len()
+    }
+
+    #[test]
+    fn alternation_order_is_canonical() {
+        let ab = Re::parse("(a|\"bc\")&!ε").unwrap();
+        let ba = Re::parse("!ε&(\"bc\"|a)").unwrap();
+        assert_eq!(ab, ba);
+    }
+
+    #[test]
+    fn canonical_order_shrinks_dfas() {
+        // The overlapping digits of the check in src/bin/dr.rs. These took 26
+        // and 205 states before Alt and And operands were sorted.
+        let cdb = NUL.neg()
+            * consider(
+                vec![toppings("cdb"), sundae("cdb") * toppings("db").star().neg()],
+                2,
+                3,
+            );
+        assert_eq!(expand_all(&mut compile(cdb)), 22);
+        let overlapping = consider(
+            vec![sundae("a"), sundae("ab"), sundae("b"), sundae("ba")],
+            0,
+            5,
+        );
+        assert_eq!(expand_all(&mut compile(overlapping)), 64);
+    }
 }
@@ -0,0 +1,1025 @@
+//! A text syntax for [`Re`].
+//!
+//! The grammar, from loosest to tightest binding:
+//!
+//! ```text
+//! alt     := and ('|' and)*
+//! and     := seq ('&' seq)*
+//! seq     := unary*
+//! unary   := '!' unary | postfix
+//! postfix := atom ('*' | '+' | '?' | '{' number (',' number?)? '}')*
+//! atom    := char | '.' | class | string | 'ε' | '∅' | '(' alt ')' | group | call
+//! group   := '(' '?' '<' name '>' alt ')'
+//!          | '(' '?' ('=' | '!' | '<=' | '<!') alt ')'
+//! class   := '[' '^'? (char ('-' char)?)* ']'
+//! string  := '"' char* '"'
+//! call    := '%' name '(' alt (',' alt)* (';' number (',' number)*)* ')'
+//!          | '%' 'count' '(' alt (',' alt)* (';' counts)* ')'
+//! counts  := number ('-' number?)? (',' number ('-' number?)?)*
+//! ```
+//!
+//! A bare `char` is a single character class, `.` matches any character and a
+//! quoted `string` is a literal. `ε` matches the empty string and `∅` matches
+//! nothing. `!` is complement, `&` is intersection and juxtaposition is
+//! concatenation. `r+` and `r?` are shorthand for [`Re::sun`] and
+//! [`Re::fickle`], `r{n}` is [`Re::fan`], and `r{m,n}` and `r{m,}` are
+//! [`Re::repeat`]. A `group` is a capture group
+//! named by a run of letters, digits and `_`, as built by [`Re::capture`].
+//! `(?=r)`, `(?!r)`, `(?<=r)` and `(?<!r)` are [`lookahead`],
+//! [`negative_lookahead`], [`lookbehind`] and [`negative_lookbehind`].
+//!
+//! [`lookahead`]: crate::re::lookahead
+//! [`negative_lookahead`]: crate::re::negative_lookahead
+//! [`lookbehind`]: crate::re::lookbehind
+//! [`negative_lookbehind`]: crate::re::negative_lookbehind
+//!
+//! The combinators that carry extra state are written as calls, with the
+//! expressions first and the numbers after a `;`:
+//!
+//! - `%fan(r; count)` is `r.fan(count)`
+//! - `%moon(r; planet)` is `r.moon(planet)` and `%moon(r; phase, planet)` is
+//!   `r.moon_phase(phase, planet)`
+//! - `%repeat_mod(r; residue, modulus, min_count)` is
+//!   `r.repeat_mod(residue, modulus, min_count)`
+//! - `%consider(d0, d1, ...; target, within)` is
+//!   `consider(vec![d0, d1, ...], target, within)`, and a leading third number
+//!   sets the running value: `%consider(d0, d1, ...; value, target, within)`
+//! - `%consider_lsd(d0, d1, ...; target, within)` is
+//!   `consider_lsd(vec![d0, d1, ...], target, within)`. Its running state is
+//!   three numbers, the value, sum and place, written before the target
+//! - Either may accept several targets, as in
+//!   `%consider(d0, d1, ...; t0, t1; within)`, which is
+//!   `consider_any(vec![d0, d1, ...], vec![t0, t1], within)`. The running
+//!   state then gets a list of its own before the targets. Moduli and targets
+//!   may be as large as a `u128`
+//! - `%count(r, x0, x1, ...; k0; k1; ...)` is `r` repeated while counting,
+//!   as a large `Fan` or `Repeat` derives to (see [`crate::count`]): each
+//!   residual `xi` followed by `r` repeated any number of times in `ki`. A set
+//!   of counts is a comma-separated list of numbers, ranges `lo-hi` and
+//!   unbounded ranges `lo-`
+//! - `%luhn(d0, d1, ...)`, `%digit_sum(d0, d1, ...; target, within)` and
+//!   `%weighted_sum(d0, d1, ...; w0, w1, ...; target, within)` are the
+//!   checksums of [`crate::checksum`]. Their running state is printed as
+//!   `%checksum(d0, d1, ...; within, from_right, pos; row; ...; sums; targets)`
+//!   with one row of contributions per slot
+//! - `%ambiguous(head, tail, d0, d1, ...)` matches what `head`, then any
+//!   number of the `di`, then `tail` match in at least two ways, as used by
+//!   the parse modes of [`crate::parses`]. A set of targets written as
+//!   `!t0, t1, ...` in `%consider` and `%consider_lsd` is every residue but
+//!   those
+//! - `%length(lo-hi)` is `Re::length_between(lo, Some(hi))`, with `lo-` for
+//!   no upper bound and a single number for an exact length, and
+//!   `%length_mod(residue, modulus)` is `Re::length_mod(residue, modulus)`
+//! - `%shuffle(r0, r1, ...)` is `r0.shuffle(r1).shuffle(...)`, every
+//!   interleaving of one string from each `ri`
+//! - `%nocase(r)` is `r.case_insensitive()`. It is expanded while parsing, so
+//!   it never shows up when printing
+//!
+//! Whitespace is only skipped around numbers; everywhere else it is a literal
+//! character. Any character can be escaped with a backslash to lose its special
+//! meaning, and `\n`, `\r`, `\t`, `\0`, `\u{hex}` and `\xHH` are also
+//! understood. `\xHH` is the char U+00HH, which is how byte-level patterns
+//! (see [`crate::bytes`]) spell bytes. The `,` and `;` separators are only
+//! special inside a call.
+//!
+//! Alternation and intersection never merge character classes, so `a|b` and
+//! `[ab]` match the same strings but are different values.
+//!
+//! `Re`'s `Display` impl prints this syntax, including the running state of
+//! `%moon` and `%consider`, so a printed derivative parses back to itself.
+//! Custom nodes are the exception: there is no syntax for them.
+
+use std::fmt::{self, Display, Formatter};
+use std::result;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::charmap::Charset;
+use crate::charrange::CharRange;
+use crate::checksum::{digit_sum, luhn, weighted_sum, ChecksumRule};
+use crate::count::{self, Counts};
+use crate::intern::ReRef;
+use crate::parses;
+use crate::re::{Lookaround, Re, Res, EPS, NUL};
+use crate::residue::Residues;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    offset: usize,
+    expected: &'static str,
+}
+
+impl ParseError {
+    /// The byte offset into the pattern where parsing failed.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// A description of what the parser wanted to see at `offset`.
+    pub fn expected(&self) -> &'static str {
+        self.expected
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "expected {} at byte {}", self.expected, self.offset)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+type Result<T> = std::result::Result<T, ParseError>;
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let ch = self.peek()?;
+        self.pos += ch.len_utf8();
+        Some(ch)
+    }
+
+    fn eat(&mut self, ch: char) -> bool {
+        if self.peek() == Some(ch) {
+            self.pos += ch.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error<T>(&self, expected: &'static str) -> Result<T> {
+        Err(ParseError {
+            offset: self.pos,
+            expected,
+        })
+    }
+
+    fn expect(&mut self, ch: char, expected: &'static str) -> Result<()> {
+        if self.eat(ch) {
+            Ok(())
+        } else {
+            self.error(expected)
+        }
+    }
+
+    /// Consumes characters while `pred` holds and returns them.
+    fn eat_while<F: Fn(char) -> bool>(&mut self, pred: F) -> &'a str {
+        let start = self.pos;
+        while self.peek().is_some_and(&pred) {
+            self.bump();
+        }
+        &self.src[start..self.pos]
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.eat_while(char::is_whitespace);
+    }
+
+    /// Whether `ch` ends the current sequence. `in_call` makes the call
+    /// separators special.
+    fn ends_seq(ch: char, in_call: bool) -> bool {
+        match ch {
+            '|' | '&' | ')' => true,
+            ',' | ';' => in_call,
+            _ => false,
+        }
+    }
+
+    fn alt(&mut self, in_call: bool) -> Result<Re> {
+        let mut parts = vec![ReRef::from(self.and(in_call)?)];
+        while self.eat('|') {
+            parts.push(ReRef::from(self.and(in_call)?));
+        }
+        Ok(Re::alt(parts).as_ref().clone())
+    }
+
+    fn and(&mut self, in_call: bool) -> Result<Re> {
+        let mut parts = vec![ReRef::from(self.seq(in_call)?)];
+        while self.eat('&') {
+            parts.push(ReRef::from(self.seq(in_call)?));
+        }
+        Ok(Re::and(parts).as_ref().clone())
+    }
+
+    fn seq(&mut self, in_call: bool) -> Result<Re> {
+        let mut parts = vec![];
+        while let Some(ch) = self.peek() {
+            if Self::ends_seq(ch, in_call) {
+                break;
+            }
+            parts.push(self.unary()?);
+        }
+        // Folding from the left lets a lookbehind see everything before it.
+        Ok(parts
+            .into_iter()
+            .fold(EPS, |init, part| Re::seq(init, part).as_ref().clone()))
+    }
+
+    fn unary(&mut self) -> Result<Re> {
+        if self.eat('!') {
+            Ok(Re::neg_rc(self.unary()?).as_ref().clone())
+        } else {
+            self.postfix()
+        }
+    }
+
+    fn postfix(&mut self) -> Result<Re> {
+        let mut re = self.atom()?;
+        loop {
+            if self.eat('*') {
+                re = re.star();
+            } else if self.eat('+') {
+                re = re.sun();
+            } else if self.eat('?') {
+                re = re.fickle();
+            } else if self.eat('{') {
+                let count = self.number()?;
+                if self.eat(',') {
+                    self.skip_whitespace();
+                    let max = if self.peek() == Some('}') {
+                        None
+                    } else {
+                        Some(self.number()?)
+                    };
+                    self.expect('}', "`}`")?;
+                    re = re.repeat(count, max);
+                } else {
+                    self.expect('}', "`}`")?;
+                    re = re.fan(count);
+                }
+            } else {
+                return Ok(re);
+            }
+        }
+    }
+
+    fn atom(&mut self) -> Result<Re> {
+        let start = self.pos;
+        match self.bump() {
+            None => self.error("an expression"),
+            Some('.') => Ok(Re::Chars(Charset::all())),
+            Some('ε') => Ok(EPS),
+            Some('∅') => Ok(NUL),
+            Some('(') => {
+                let open = if !self.eat('?') {
+                    None
+                } else if self.eat('=') {
+                    Some(Ok(Lookaround::Ahead))
+                } else if self.eat('!') {
+                    Some(Ok(Lookaround::NotAhead))
+                } else {
+                    self.expect('<', "`<`, `=` or `!`")?;
+                    if self.eat('=') {
+                        Some(Ok(Lookaround::Behind))
+                    } else if self.eat('!') {
+                        Some(Ok(Lookaround::NotBehind))
+                    } else {
+                        let name = self.eat_while(is_name_char);
+                        if name.is_empty() {
+                            return self.error("a group name, `=` or `!`");
+                        }
+                        self.expect('>', "`>`")?;
+                        Some(Err(name))
+                    }
+                };
+                let re = self.alt(false)?;
+                self.expect(')', "`)`")?;
+                Ok(match open {
+                    None => re,
+                    Some(Ok(look)) => Re::Look(look, ReRef::from(re)),
+                    Some(Err(name)) => re.capture(name),
+                })
+            }
+            Some('[') => self.class(),
+            Some('"') => self.string(),
+            Some('%') => self.call(),
+            Some('*') | Some('+') | Some('?') | Some('{') | Some('}') | Some(']') => {
+                self.pos = start;
+                self.error("an expression")
+            }
+            Some('\\') => Ok(Re::from(self.escape()?)),
+            Some(ch) => Ok(Re::from(ch)),
+        }
+    }
+
+    /// Parses the rest of an escape sequence, after the backslash.
+    fn escape(&mut self) -> Result<char> {
+        match self.bump() {
+            None => self.error("an escaped character"),
+            Some('n') => Ok('\n'),
+            Some('r') => Ok('\r'),
+            Some('t') => Ok('\t'),
+            Some('0') => Ok('\0'),
+            Some('x') => {
+                let digits = self.src[self.pos..]
+                    .get(..2)
+                    .filter(|d| d.bytes().all(|b| b.is_ascii_hexdigit()));
+                match digits.and_then(|d| u8::from_str_radix(d, 16).ok()) {
+                    Some(b) => {
+                        self.pos += 2;
+                        Ok(char::from(b))
+                    }
+                    None => self.error("two hexadecimal digits"),
+                }
+            }
+            Some('u') => {
+                self.expect('{', "`{`")?;
+                let start = self.pos;
+                let ch = u32::from_str_radix(self.eat_while(|ch| ch.is_ascii_hexdigit()), 16)
+                    .ok()
+                    .and_then(std::char::from_u32);
+                match ch {
+                    Some(ch) => {
+                        self.expect('}', "`}`")?;
+                        Ok(ch)
+                    }
+                    None => {
+                        self.pos = start;
+                        self.error("a hexadecimal code point")
+                    }
+                }
+            }
+            Some(ch) => Ok(ch),
+        }
+    }
+
+    fn class_char(&mut self) -> Result<char> {
+        match self.bump() {
+            None => self.error("`]`"),
+            Some('\\') => self.escape(),
+            Some(ch) => Ok(ch),
+        }
+    }
+
+    fn class(&mut self) -> Result<Re> {
+        let negated = self.eat('^');
+        let mut set = Charset::new();
+        while !self.eat(']') {
+            let start = self.class_char()?;
+            if self.eat('-') {
+                let offset = self.pos;
+                let end = self.class_char()?;
+                if end < start {
+                    self.pos = offset;
+                    return self.error("a range end after its start");
+                }
+                set.insert(CharRange::from(start..=end));
+            } else {
+                set.insert_char(start);
+            }
+        }
+        if negated {
+            set.invert();
+        }
+        Ok(Re::Chars(set))
+    }
+
+    fn string(&mut self) -> Result<Re> {
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                None => return self.error("`\"`"),
+                Some('"') => break,
+                Some('\\') => s.push(self.escape()?),
+                Some(ch) => s.push(ch),
+            }
+        }
+        Ok(Re::Lit(s.into()))
+    }
+
+    fn number<T: FromStr>(&mut self) -> Result<T> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let number = self.eat_while(|ch| ch.is_ascii_digit()).parse();
+        match number {
+            Ok(n) => {
+                self.skip_whitespace();
+                Ok(n)
+            }
+            Err(_) => {
+                self.pos = start;
+                self.error("a number")
+            }
+        }
+    }
+
+    /// A set of counts: numbers and ranges, where `lo-` has no upper bound.
+    fn counts(&mut self) -> Result<Counts> {
+        let mut ranges = vec![];
+        loop {
+            let lo: usize = self.number()?;
+            let hi = if self.eat('-') {
+                self.skip_whitespace();
+                if self.peek().is_some_and(|ch| ch.is_ascii_digit()) {
+                    let start = self.pos;
+                    let hi = self.number()?;
+                    if hi < lo {
+                        self.pos = start;
+                        return self.error("a range in increasing order");
+                    }
+                    Some(hi)
+                } else {
+                    None
+                }
+            } else {
+                Some(lo)
+            };
+            ranges.push((lo, hi));
+            if !self.eat(',') {
+                return Ok(Counts::from_ranges(ranges));
+            }
+        }
+    }
+
+    /// The arguments of `%length` or `%length_mod`, which take no
+    /// expressions, and the closing `)`.
+    fn length(&mut self, modular: bool) -> Result<Re> {
+        let start = self.pos;
+        let re = if modular {
+            let residue = self.number()?;
+            self.expect(',', "`,`")?;
+            self.skip_whitespace();
+            let modulus_start = self.pos;
+            let modulus = self.number()?;
+            if modulus == 0 {
+                self.pos = modulus_start;
+                return self.error("a non-zero modulus");
+            }
+            Re::length_mod(residue, modulus)
+        } else {
+            let counts = self.counts()?;
+            match counts.ranges().collect::<Vec<_>>()[..] {
+                [(min, max)] => Re::length_between(min, max),
+                _ => {
+                    self.pos = start;
+                    return self.error("a single range of lengths");
+                }
+            }
+        };
+        self.expect(')', "`)`")?;
+        Ok(re)
+    }
+
+    fn call(&mut self) -> Result<Re> {
+        let name_start = self.pos;
+        let name = self.eat_while(|ch| ch.is_ascii_lowercase() || ch == '_');
+        if !matches!(
+            name,
+            "fan"
+                | "moon"
+                | "repeat_mod"
+                | "length"
+                | "length_mod"
+                | "shuffle"
+                | "consider"
+                | "consider_lsd"
+                | "nocase"
+                | "count"
+                | "checksum"
+                | "digit_sum"
+                | "weighted_sum"
+                | "luhn"
+                | "ambiguous"
+        ) {
+            self.pos = name_start;
+            return self.error("the name of a combinator");
+        }
+        self.expect('(', "`(`")?;
+        if name.starts_with("length") {
+            return self.length(name == "length_mod");
+        }
+        let mut res = vec![self.alt(true)?];
+        while self.eat(',') {
+            res.push(self.alt(true)?);
+        }
+        let mut sections = vec![];
+        let mut counts = vec![];
+        let mut excluded = None;
+        while self.eat(';') {
+            if name.starts_with("consider") {
+                self.skip_whitespace();
+                if self.eat('!') {
+                    excluded = Some(sections.len());
+                }
+            }
+            if name == "count" {
+                counts.push(self.counts()?);
+            } else {
+                let mut numbers: Vec<u128> = vec![self.number()?];
+                while self.eat(',') {
+                    numbers.push(self.number()?);
+                }
+                sections.push(numbers);
+            }
+        }
+        let args_end = self.pos;
+        self.expect(')', "`)`")?;
+
+        let arity_error = |expected| {
+            Err(ParseError {
+                offset: args_end,
+                expected,
+            })
+        };
+        if name == "consider" || name == "consider_lsd" {
+            let lsd = name == "consider_lsd";
+            return consider_state(res, &sections, excluded, lsd).map_err(|expected| ParseError {
+                offset: args_end,
+                expected,
+            });
+        }
+        if sections.len() > 1 && !matches!(name, "checksum" | "weighted_sum") {
+            return arity_error("a single list of numbers");
+        }
+        let sections = match sections
+            .iter()
+            .map(|numbers| {
+                numbers
+                    .iter()
+                    .map(|&n| std::convert::TryFrom::try_from(n).ok())
+                    .collect()
+            })
+            .collect::<Option<Vec<Vec<usize>>>>()
+        {
+            Some(sections) => sections,
+            None => return arity_error("numbers that fit in a `usize`"),
+        };
+        let numbers = sections.first().cloned().unwrap_or_default();
+        match name {
+            "fan" => match (&res[..], &numbers[..]) {
+                ([re], [count]) => Ok(re.clone().fan(*count)),
+                _ => arity_error("one expression and a count"),
+            },
+            "moon" => match (&res[..], &numbers[..]) {
+                ([re], [planet]) => Ok(re.clone().moon(*planet)),
+                ([re], [phase, planet]) => Ok(re.clone().moon_phase(*phase, *planet)),
+                _ => arity_error("one expression, an optional phase and a planet"),
+            },
+            "repeat_mod" => match (&res[..], &numbers[..]) {
+                (_, [_, 0, _]) => arity_error("a non-zero modulus"),
+                (_, &[residue, modulus, min_count])
+                    if Re::first_count(residue, modulus, min_count).is_none() =>
+                {
+                    arity_error("a first count that fits in a `usize`")
+                }
+                ([re], [residue, modulus, min_count]) => {
+                    Ok(re.clone().repeat_mod(*residue, *modulus, *min_count))
+                }
+                _ => arity_error("one expression, a residue, a modulus and a minimum count"),
+            },
+            "count" => {
+                if counts.len() + 1 != res.len() {
+                    return arity_error("one set of counts for each residual");
+                }
+                let mut res = res.into_iter().map(ReRef::from);
+                let re = res.next().expect("calls have an expression");
+                Ok(count::count_rc(re, res.zip(counts)).as_ref().clone())
+            }
+            "ambiguous" => match (&res[..], &sections[..]) {
+                ([head, tail, choices @ ..], []) if !choices.is_empty() => {
+                    let choices = choices.iter().cloned().map(ReRef::from).collect::<Vec<_>>();
+                    let (head, tail) = (ReRef::from(head.clone()), ReRef::from(tail.clone()));
+                    Ok(parses::ambiguous(Arc::new(Res::from(choices)), head, tail)
+                        .as_ref()
+                        .clone())
+                }
+                _ => arity_error("a head, a tail and at least one choice, and no numbers"),
+            },
+            "shuffle" => match &sections[..] {
+                [] => Ok(res
+                    .into_iter()
+                    .reduce(Re::shuffle)
+                    .expect("calls have an expression")),
+                _ => arity_error("no numbers"),
+            },
+            "luhn" => match &sections[..] {
+                [] => Ok(luhn(res)),
+                _ => arity_error("no numbers"),
+            },
+            "digit_sum" => match numbers[..] {
+                [_, 0] => arity_error("a non-zero modulus"),
+                [target, within] => Ok(digit_sum(res, target, within)),
+                _ => arity_error("a target and a modulus"),
+            },
+            "weighted_sum" => match &sections[..] {
+                [_, modulus] if modulus.get(1) == Some(&0) => arity_error("a non-zero modulus"),
+                [weights, modulus] => match modulus[..] {
+                    [target, within] => Ok(weighted_sum(res, weights, target, within)),
+                    _ => arity_error("a target and a modulus"),
+                },
+                _ => arity_error("the weights, then a target and a modulus"),
+            },
+            "checksum" => checksum_state(res, sections).ok_or(ParseError {
+                offset: args_end,
+                expected: "a modulus, direction and position, a row of contributions \
+                           per slot, and the sums and targets below the modulus",
+            }),
+            "nocase" => match (&res[..], &numbers[..]) {
+                ([re], []) => Ok(re.case_insensitive()),
+                _ => arity_error("one expression"),
+            },
+            _ => unreachable!("checked against the list of names"),
+        }
+    }
+}
+
+fn is_name_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_'
+}
+
+/// Whether `name` can name a group in the text syntax: a run of letters,
+/// digits and `_` that is not empty.
+pub(crate) fn is_group_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(is_name_char)
+}
+
+/// Builds a `Consider` or `ConsiderLsd` node from the numbers after the `;`.
+/// The running state and the targets are either one list, with a single
+/// target, or lists of their own. `excluded` is the index of the list marked
+/// with a `!`, which may only be the targets.
+fn consider_state(
+    res: Vec<Re>,
+    sections: &[Vec<u128>],
+    excluded: Option<usize>,
+    lsd: bool,
+) -> result::Result<Re, &'static str> {
+    if excluded.is_some_and(|index| sections.len() < 2 || index != sections.len() - 2) {
+        return Err("`!` only before a list of targets");
+    }
+    let state_len = if lsd { 3 } else { 1 };
+    let split = |numbers: &[u128]| match numbers.len() - 2 {
+        0 => Some((None, numbers[0])),
+        n if n == state_len => Some((Some(numbers[..n].to_vec()), numbers[n])),
+        _ => None,
+    };
+    let (state, targets, within) = match sections {
+        [numbers] if numbers.len() >= 2 => match split(numbers) {
+            Some((state, target)) => (state, vec![target], &numbers[numbers.len() - 1..]),
+            None => return Err(CONSIDER_ARITY),
+        },
+        [targets, within] => (None, targets.clone(), &within[..]),
+        [state, targets, within] if state.len() == state_len => {
+            (Some(state.clone()), targets.clone(), &within[..])
+        }
+        _ => return Err(CONSIDER_ARITY),
+    };
+    let within = match within {
+        [0] => return Err("a non-zero modulus"),
+        &[within] => within,
+        _ => return Err(CONSIDER_ARITY),
+    };
+    if targets.iter().any(|&t| t >= within) {
+        return Err("targets below the modulus");
+    }
+    let state = state.unwrap_or_else(|| if lsd { vec![0, 0, 1 % within] } else { vec![0] });
+    if state.iter().any(|&n| n >= within) {
+        return Err(if lsd {
+            "a value, sum and place below the modulus"
+        } else {
+            "a value below the modulus"
+        });
+    }
+    let choices = Arc::new(Res::from(
+        res.into_iter().map(ReRef::from).collect::<Vec<_>>(),
+    ));
+    let mut targets = Residues::new(targets, within);
+    if excluded.is_some() {
+        targets = targets.complement();
+    }
+    Ok(match state[..] {
+        [value] => Re::Consider(choices, value, targets, within),
+        [value, sum, place] => Re::ConsiderLsd(choices, value, sum, place, targets, within),
+        _ => unreachable!("state lengths were checked"),
+    })
+}
+
+const CONSIDER_ARITY: &str = "an optional running state, then targets and a modulus";
+
+/// Rebuilds a `Checksum` node from its printed numbers, if they fit
+/// together.
+fn checksum_state(res: Vec<Re>, mut sections: Vec<Vec<usize>>) -> Option<Re> {
+    let targets = sections.pop()?;
+    let sums = sections.pop()?;
+    let (within, from_right, pos) = match sections.first()?[..] {
+        [within, from_right, pos] if within > 0 && from_right < 2 => (within, from_right, pos),
+        _ => return None,
+    };
+    let table = sections.split_off(1);
+    let p = table.len();
+    let fits =
+        |numbers: &Vec<usize>, len| numbers.len() == len && numbers.iter().all(|&n| n < within);
+    if pos >= p
+        || !table.iter().all(|row| fits(row, res.len()))
+        || !fits(&sums, p)
+        || !fits(&targets, p)
+    {
+        return None;
+    }
+    let rule = ChecksumRule {
+        choices: Res::from(res.into_iter().map(ReRef::from).collect::<Vec<_>>()),
+        table,
+        from_right: from_right == 1,
+        within,
+    };
+    Some(Re::Checksum(Arc::new(rule), pos, sums, targets))
+}
+
+impl Re {
+    /// Parses a pattern written in the syntax described in [`crate::parse`].
+    pub fn parse(src: &str) -> Result<Re> {
+        let mut parser = Parser { src, pos: 0 };
+        let re = parser.alt(false)?;
+        if parser.pos < src.len() {
+            return parser.error("end of pattern");
+        }
+        Ok(re)
+    }
+}
+
+impl FromStr for Re {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Re> {
+        Re::parse(s)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cheese, compile, consider, consider_any, consider_lsd, sundae, toppings};
+
+    fn parse(s: &str) -> Re {
+        Re::parse(s).unwrap_or_else(|e| panic!("{:?}: {}", s, e))
+    }
+
+    #[test]
+    fn parses_combinator_forms() {
+        assert_eq!(parse("\"hello\""), sundae("hello"));
+        assert_eq!(parse("[a-c]"), cheese('a'..='c'));
+        assert_eq!(parse("[cdb]"), toppings("cdb"));
+        assert_eq!(parse("[0-9]*"), cheese('0'..='9').star());
+        assert_eq!(parse("[1-3]+"), cheese('1'..='3').sun());
+        assert_eq!(parse(",?"), toppings(",").fickle());
+        assert_eq!(parse("!∅"), NUL.neg());
+        assert_eq!(parse("[0-9]{7}"), cheese('0'..='9').fan(7));
+        assert_eq!(parse("%fan([0-9]; 7)"), cheese('0'..='9').fan(7));
+        assert_eq!(
+            parse("%moon([a-f]; 2, 3)"),
+            cheese('a'..='f').moon_phase(2, 3)
+        );
+        assert_eq!(parse("%moon(a; 3)"), cheese('a').moon(3));
+        assert_eq!(
+            parse("%consider(0,1; 0, 3)"),
+            consider(vec![cheese('0'), cheese('1')], 0, 3)
+        );
+        assert_eq!(parse("\"10\"!ε"), sundae("10") * EPS.neg());
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(parse("ab*"), cheese('a') * cheese('b').star());
+        assert_eq!(parse("!a*"), cheese('a').star().neg());
+        assert_eq!(parse("(!a)*"), cheese('a').neg().star());
+        assert_eq!(
+            parse("a|b&c"),
+            Re::alt(vec![
+                ReRef::from(cheese('a')),
+                Re::and(vec![ReRef::from(cheese('b')), ReRef::from(cheese('c'))])
+            ])
+            .as_ref()
+            .clone()
+        );
+    }
+
+    #[test]
+    fn escapes_and_classes() {
+        assert_eq!(parse("\\*"), cheese('*'));
+        assert_eq!(parse("\\u{1f927}"), cheese('\u{1f927}'));
+        assert_eq!(parse("[\\]\\-]"), toppings("]-"));
+        assert_eq!(parse("\"a\\\"b\""), sundae("a\"b"));
+        assert_eq!(parse("()"), EPS);
+        assert_eq!(parse("\\xff\\x0a"), cheese('\u{ff}') * cheese('\n'));
+        assert_eq!(parse("[\\x00-\\x7f]"), cheese(0u8..=0x7f));
+        assert_eq!(Re::parse("\\x4").unwrap_err().offset(), 2);
+        assert_eq!(Re::parse("\\x+1").unwrap_err().offset(), 2);
+        assert_eq!(Re::parse("\\x-1").unwrap_err().offset(), 2);
+
+        let mut m = compile(parse("[^a-c]"));
+        assert!(m.matches("d"));
+        assert!(!m.matches("b"));
+    }
+
+    #[test]
+    fn commas_are_literal_outside_calls() {
+        let mut m = compile(parse("(,? *(\"cough\"|\"runny nose\"))+"));
+        assert!(m.matches("cough, runny nose"));
+        assert!(!m.matches("cough; runny nose"));
+
+        let mut m = compile(parse("%consider(0, 1; 0, 3)&!ε"));
+        assert!(m.matches("0"));
+        assert!(!m.matches(" 1"));
+    }
+
+    #[test]
+    fn counted_repetition() {
+        assert_eq!(parse("a{3,8}"), cheese('a').repeat(3, Some(8)));
+        assert_eq!(parse("a{ 2 , }"), cheese('a').at_least(2));
+        assert_eq!(parse("a{2}"), cheese('a').fan(2));
+        assert_eq!(Re::parse("a{2,x}").unwrap_err().offset(), 4);
+    }
+
+    #[test]
+    fn count_call() {
+        let re = parse("%count([0-9]x?,x?,ε; 3-5, 6, 9-; 2)");
+        assert_eq!(parse(&re.to_string()), re);
+        let mut m = compile(re);
+        assert!(m.matches("x123"));
+        assert!(!m.matches("x12"));
+        assert!(m.matches("1x2x3x4"));
+        assert!(!m.matches("1234567"));
+        assert_eq!(parse("%count(a,b; 0)"), cheese('b'));
+        assert_eq!(Re::parse("%count(a,b; 3-2)").unwrap_err().offset(), 14);
+        assert_eq!(Re::parse("%count(a,b)").unwrap_err().offset(), 10);
+    }
+
+    #[test]
+    fn length_calls() {
+        assert_eq!(parse("%length(3-5)"), Re::length_between(3, Some(5)));
+        assert_eq!(parse("%length(3-)"), Re::length_between(3, None));
+        assert_eq!(
+            parse("%length(4)a*"),
+            Re::length_between(4, Some(4)) * cheese('a').star()
+        );
+        assert_eq!(parse("%length_mod(4, 3)"), Re::length_mod(1, 3));
+        assert_eq!(Re::parse("%length(1,3)").unwrap_err().offset(), 8);
+        assert_eq!(Re::parse("%length_mod(1, 0)").unwrap_err().offset(), 15);
+        assert_eq!(Re::parse("%length(a)").unwrap_err().offset(), 8);
+    }
+
+    #[test]
+    fn checksum_calls() {
+        let digits = || ('0'..='9').map(cheese).collect::<Vec<_>>();
+        assert_eq!(parse("%luhn(0,1,2,3,4,5,6,7,8,9)"), crate::luhn(digits()));
+        assert_eq!(
+            parse("%weighted_sum(0,1,2,3,4,5,6,7,8,9; 1, 3; 0, 10)"),
+            crate::weighted_sum(digits(), &[1, 3], 0, 10)
+        );
+        assert_eq!(
+            parse("%digit_sum(a,b; 1, 2)"),
+            crate::digit_sum(vec![cheese('a'), cheese('b')], 1, 2)
+        );
+        assert_eq!(
+            parse("%checksum(a,b; 3, 1, 1; 0, 1; 0, 2; 1, 0; 2, 2)").to_string(),
+            "%checksum(a,b; 3, 1, 1; 0, 1; 0, 2; 1, 0; 2, 2)"
+        );
+        assert_eq!(
+            Re::parse("%checksum(a,b; 3, 1, 2; 0, 1; 0, 2; 1, 0; 2, 2)")
+                .unwrap_err()
+                .offset(),
+            46
+        );
+        assert_eq!(Re::parse("%digit_sum(a; 1, 0)").unwrap_err().offset(), 18);
+        assert_eq!(Re::parse("%fan(a; 1; 2)").unwrap_err().offset(), 12);
+    }
+
+    #[test]
+    fn groups() {
+        assert_eq!(parse("(?<n1>a)*"), cheese('a').capture("n1").star());
+        assert_eq!(Re::parse("(?<>a)").unwrap_err().offset(), 3);
+        assert_eq!(Re::parse("(?a)").unwrap_err().offset(), 2);
+        assert!(is_group_name("n_1") && !is_group_name("a b") && !is_group_name(""));
+        let caps = compile(parse("(?<head>.*)-(?<tail>[^-]*)"))
+            .captures("a-b-c")
+            .unwrap();
+        assert_eq!(caps.get("head"), Some((0, 3)));
+        assert_eq!(caps.get("tail"), Some((4, 5)));
+    }
+
+    #[test]
+    #[should_panic(expected = "not a valid group name")]
+    fn unprintable_group_names() {
+        cheese('a').capture("a b");
+    }
+
+    #[test]
+    fn lookaround() {
+        use crate::{lookahead, lookbehind, negative_lookbehind};
+        assert_eq!(
+            parse("a(?=b)c"),
+            cheese('a') * lookahead(cheese('b')) * cheese('c')
+        );
+        assert_eq!(
+            parse("xy(?<=\"xy\")z(?<!z)"),
+            cheese('x')
+                * cheese('y')
+                * lookbehind(sundae("xy"))
+                * cheese('z')
+                * negative_lookbehind(cheese('z'))
+        );
+        let mut m = compile(parse("!∅(?<!cdb)a(?=[0-9])!∅"));
+        assert!(m.matches("cdxa1"));
+        assert!(!m.matches("cdba1"));
+        assert_eq!(Re::parse("(?<").unwrap_err().offset(), 3);
+    }
+
+    #[test]
+    fn nocase_call() {
+        assert_eq!(parse("%nocase(\"a1\")"), toppings("Aa") * sundae("1"));
+        let mut m = compile(parse("%nocase(!\"no\")"));
+        assert!(m.matches("yes"));
+        assert!(!m.matches("nO"));
+        assert_eq!(Re::parse("%nocase(a; 1)").unwrap_err().offset(), 12);
+    }
+
+    #[test]
+    fn consider_state_is_checked() {
+        assert_eq!(
+            parse("%consider_lsd(0,1; 1, 3)"),
+            consider_lsd(vec![cheese('0'), cheese('1')], 1, 3)
+        );
+        assert_eq!(Re::parse("%consider(a; 3, 1, 3)").unwrap_err().offset(), 20);
+        assert_eq!(
+            Re::parse("%consider_lsd(a; 0, 3, 1, 1, 3)")
+                .unwrap_err()
+                .offset(),
+            30
+        );
+        assert_eq!(
+            Re::parse("%consider_lsd(a; 1, 1, 3)").unwrap_err().offset(),
+            24
+        );
+    }
+
+    #[test]
+    fn consider_target_sets() {
+        let digits = || ('0'..='6').map(cheese).collect::<Vec<_>>();
+        assert_eq!(
+            parse("%consider(0,1,2,3,4,5,6; 1, 4; 7)"),
+            consider_any(digits(), vec![4, 1], 7)
+        );
+        let mut m = compile(parse("%consider(0,1,2,3,4,5,6; 1, 4; 7)"));
+        assert!(m.matches("11"));
+        assert!(m.matches("14"));
+        assert!(!m.matches("12"));
+        let big = parse("%consider(0,1; 7; 1, 2; 340282366920938463463374607431768211297)");
+        assert_eq!(parse(&big.to_string()), big);
+        assert_eq!(
+            Re::parse("%consider(a; 1, 7; 7)").unwrap_err().expected(),
+            "targets below the modulus"
+        );
+        assert_eq!(
+            Re::parse("%fan(a; 18446744073709551616)")
+                .unwrap_err()
+                .expected(),
+            "numbers that fit in a `usize`"
+        );
+    }
+
+    #[test]
+    fn insurance_pieces() {
+        let mut m = compile(parse(
+            "!∅%consider([cdb],\"cdb\"![db]*; 2, 3)\
+             &!∅[1-3]+[3-7]+!∅\
+             &%consider([05a],[16b],[27cf],[38dx],[49e]; 0, 7)\
+             &%moon(%moon([0-9]; 1, 3)%moon([a-f]; 2, 3); 0, 2)\
+             &%moon(\"10\"!ε; 0, 3)",
+        ));
+        assert!(m.matches("10174cdbf10810c"));
+        assert!(!m.matches("10174cdbf10810d"));
+    }
+
+    #[test]
+    fn errors_carry_offset_and_expectation() {
+        let err = Re::parse("ab)").unwrap_err();
+        assert_eq!((err.offset(), err.expected()), (2, "end of pattern"));
+
+        let err = Re::parse("[a-").unwrap_err();
+        assert_eq!((err.offset(), err.expected()), (3, "`]`"));
+
+        let err = Re::parse("a{x}").unwrap_err();
+        assert_eq!((err.offset(), err.expected()), (2, "a number"));
+
+        let err = Re::parse("%fun(a)").unwrap_err();
+        assert_eq!(err.offset(), 1);
+
+        let err = Re::parse("%consider(a; 1, 0)").unwrap_err();
+        assert_eq!(err.expected(), "a non-zero modulus");
+
+        assert!(Re::parse("%repeat_mod(a; 5, 18446744073709551615, 3)").is_ok());
+        let err = Re::parse("%repeat_mod(a; 0, 2, 18446744073709551615)").unwrap_err();
+        assert_eq!(err.expected(), "a first count that fits in a `usize`");
+
+        let err = Re::parse("*").unwrap_err();
+        assert_eq!((err.offset(), err.expected()), (0, "an expression"));
+    }
+}
@@ -0,0 +1,250 @@
+//! How many ways a `Consider` may read its input.
+//!
+//! A parse splits the input into non-empty pieces, each matching one of the
+//! choices. When the choices overlap, as `[cdb]` and `cdb` do, one input can
+//! have several parses and so several values. A plain `Consider` matches if
+//! any of them is accepted. [`consider_parses`] can instead ask for every
+//! parse to be accepted, or for there to be exactly one parse.
+//!
+//! Both are built out of plain `Consider`s. Every parse is accepted when some
+//! parse is and none is left out, which is a `Consider` of the other
+//! residues. There is exactly one parse when some parse is accepted and the
+//! input is not ambiguous, which is what the `Ambiguous` node matches: the
+//! strings that `head`, then any number of choices, then `tail` match in at
+//! least two ways. Deriving it follows each way the next char can be read,
+//! and two of them both going on to match is as good as one of them matching
+//! twice over.
+
+use std::sync::Arc;
+
+use crate::charrange::CharRange;
+use crate::intern::ReRef;
+use crate::re::{consider_any, consider_lsd_any, sundae, toppings, Re, Res, EPS};
+
+/// Which parses of its input a `Consider` needs to accept.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Parses {
+    /// Some parse is accepted. This is what a plain `Consider` does.
+    Any,
+    /// There is a parse, and every parse is accepted.
+    All,
+    /// There is exactly one parse, and it is accepted.
+    Unique,
+}
+
+/// Matches what `head`, then any number of `choices`, then `tail` match in
+/// at least two ways.
+pub(crate) fn ambiguous(choices: Arc<Res>, head: ReRef, tail: ReRef) -> ReRef {
+    if choices.is_empty() || *head == Re::Nul || *tail == Re::Nul {
+        return ReRef::from(Re::Nul);
+    }
+    ReRef::from(Re::Ambiguous(choices, head, tail))
+}
+
+/// Where one way of reading the input has got to: in `head` or a choice,
+/// with more choices and `tail` still to come, or in `tail`.
+enum Thread {
+    Piece(ReRef),
+    Tail(ReRef),
+}
+
+pub(crate) fn derive(
+    choices: &Arc<Res>,
+    head: &ReRef,
+    tail: &ReRef,
+    ch: &char,
+) -> (CharRange, ReRef) {
+    let (mut range, derived) = head.derive(ch);
+    let mut threads = vec![Thread::Piece(derived)];
+    if head.nullable() {
+        let (r0, derived) = choices.derive(ch);
+        range = &range & &r0;
+        threads.extend(derived.into_iter().map(Thread::Piece));
+        let (r1, derived) = tail.derive(ch);
+        range = &range & &r1;
+        threads.push(Thread::Tail(derived));
+    }
+    threads.retain(|thread| match thread {
+        Thread::Piece(re) | Thread::Tail(re) => **re != Re::Nul,
+    });
+
+    let once = |thread: &Thread| match thread {
+        Thread::Piece(re) => {
+            let rest = ReRef::from(Re::Star(Re::alt(choices.iter().cloned())));
+            Re::seq(re.clone(), Re::seq(rest, tail.clone()))
+        }
+        Thread::Tail(re) => re.clone(),
+    };
+    let mut parts = vec![];
+    for (i, thread) in threads.iter().enumerate() {
+        if let Thread::Piece(re) = thread {
+            parts.push(ambiguous(choices.clone(), re.clone(), tail.clone()));
+        }
+        for other in &threads[i + 1..] {
+            parts.push(Re::and(vec![once(thread), once(other)]));
+        }
+    }
+    (range, Re::alt(parts))
+}
+
+/// Restricts a plain `Consider` to the inputs whose parses pass `parses`.
+fn restrict(any: Re, parses: Parses) -> Re {
+    let (choices, left_out) = match &any {
+        Re::Consider(choices, value, targets, within) => (
+            choices,
+            Re::Consider(choices.clone(), *value, targets.complement(), *within),
+        ),
+        Re::ConsiderLsd(choices, value, sum, place, targets, within) => (
+            choices,
+            Re::ConsiderLsd(
+                choices.clone(),
+                *value,
+                *sum,
+                *place,
+                targets.complement(),
+                *within,
+            ),
+        ),
+        _ => return any,
+    };
+    let unwanted = match parses {
+        Parses::Any => return any,
+        Parses::All => left_out,
+        Parses::Unique => {
+            let unwanted = ambiguous(choices.clone(), ReRef::from(EPS), ReRef::from(EPS));
+            unwanted.as_ref().clone()
+        }
+    };
+    any & unwanted.neg()
+}
+
+/// Like [`consider_any`], but with a choice of which parses need to be
+/// accepted.
+pub fn consider_parses<I, T>(digits: I, targets: T, within: u128, parses: Parses) -> Re
+where
+    I: IntoIterator<Item = Re>,
+    T: IntoIterator<Item = u128>,
+{
+    restrict(consider_any(digits, targets, within), parses)
+}
+
+/// Like [`consider_lsd_any`], but with a choice of which parses need to be
+/// accepted.
+pub fn consider_lsd_parses<I, T>(digits: I, targets: T, within: u128, parses: Parses) -> Re
+where
+    I: IntoIterator<Item = Re>,
+    T: IntoIterator<Item = u128>,
+{
+    restrict(consider_lsd_any(digits, targets, within), parses)
+}
+
+/// How many ways `text` reads as a number with the digits from the README,
+/// `[cdb]` for 0 and `cdb` for 1. See [`ambiguity_with`] for other digits.
+pub fn ambiguity(text: &str) -> u128 {
+    ambiguity_with(vec![toppings("cdb"), sundae("cdb")], text)
+}
+
+/// How many ways `text` splits into non-empty pieces that each match one of
+/// `digits`, counting a piece once for every digit it matches. Saturates at
+/// `u128::MAX`.
+pub fn ambiguity_with<I: IntoIterator<Item = Re>>(digits: I, text: &str) -> u128 {
+    let digits: Vec<_> = digits.into_iter().map(ReRef::from).collect();
+    let chars: Vec<char> = text.chars().collect();
+    let mut ways = vec![0u128; chars.len() + 1];
+    ways[0] = 1;
+    for start in 0..chars.len() {
+        if ways[start] == 0 {
+            continue;
+        }
+        for digit in &digits {
+            let mut rest = digit.clone();
+            for (end, ch) in chars.iter().enumerate().skip(start) {
+                rest = rest.derive(ch).1;
+                if *rest == Re::Nul {
+                    break;
+                }
+                if rest.nullable() {
+                    ways[end + 1] = ways[end + 1].saturating_add(ways[start]);
+                }
+            }
+        }
+    }
+    ways[chars.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cheese, compile};
+
+    fn digits() -> Vec<Re> {
+        vec![toppings("cdb"), sundae("cdb")]
+    }
+
+    #[test]
+    fn counts_parses() {
+        assert_eq!(ambiguity(""), 1);
+        assert_eq!(ambiguity("c"), 1);
+        assert_eq!(ambiguity("cdb"), 2);
+        assert_eq!(ambiguity("cdbcdb"), 4);
+        assert_eq!(ambiguity("cdx"), 0);
+        assert_eq!(ambiguity_with(digits(), "cdbcdb"), 4);
+        assert_eq!(ambiguity_with(vec![cheese('a'), cheese('a')], "aaa"), 8);
+    }
+
+    #[test]
+    fn selects_parses() {
+        // "cdb" reads as 000 = 0 or as 1, and "cdbc" as 0000 = 0 or 10 = 2.
+        let mut any = compile(consider_parses(digits(), vec![1], 3, Parses::Any));
+        let mut all = compile(consider_parses(digits(), vec![1], 3, Parses::All));
+        let mut unique = compile(consider_parses(digits(), vec![0], 3, Parses::Unique));
+        assert!(any.matches("cdb"));
+        assert!(!all.matches("cdb"));
+        assert!(!unique.matches("cdb"));
+        let mut all = compile(consider_parses(digits(), vec![0, 1], 3, Parses::All));
+        assert!(all.matches("cdb"));
+        assert!(!all.matches("cdbc"));
+        assert!(all.matches("ddd"));
+        assert!(!all.matches("x"));
+        assert!(unique.matches("ddd"));
+        assert!(unique.matches(""));
+        assert!(!unique.matches("dcdb"));
+
+        // Reading the reversed digits from the other end gives the same
+        // parses with the same values.
+        let reversed = || vec![toppings("cdb"), sundae("bdc")];
+        for parses in [Parses::Any, Parses::All, Parses::Unique] {
+            let mut lsd = compile(consider_lsd_parses(digits(), vec![1, 2], 3, parses));
+            let mut msd = compile(consider_parses(reversed(), vec![1, 2], 3, parses));
+            for text in ["cdb", "bcdb", "cdbc", "cdbcdb", "dcdbd", "bb"] {
+                let backwards: String = text.chars().rev().collect();
+                assert_eq!(lsd.matches(text), msd.matches(&backwards), "{:?}", text);
+            }
+        }
+    }
+
+    #[test]
+    fn agrees_with_counting() {
+        let digits = || vec![cheese('a'), sundae("ab"), sundae("ba"), cheese('b')];
+        let mut ambiguous = compile(ambiguous(
+            Arc::new(Res::from(
+                digits().into_iter().map(ReRef::from).collect::<Vec<_>>(),
+            )),
+            ReRef::from(EPS),
+            ReRef::from(EPS),
+        ));
+        for len in 0..8 {
+            for n in 0..1 << len {
+                let text: String = (0..len)
+                    .map(|bit| if n >> bit & 1 == 1 { 'b' } else { 'a' })
+                    .collect();
+                assert_eq!(
+                    ambiguous.matches(&text),
+                    ambiguity_with(digits(), &text) >= 2,
+                    "{:?}",
+                    text
+                );
+            }
+        }
+    }
+}
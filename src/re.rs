@@ -1,79 +1,236 @@
 use std::cmp::Ordering;
-use std::collections::HashSet;
-use std::hash::Hash;
-use std::ops::{BitAnd, BitOr, Mul};
-use std::rc::Rc;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::ops::{BitAnd, BitOr, Deref, Mul};
+use std::sync::Arc;
 
 use delegate::delegate;
 
 use crate::charmap::{Charset, InOrOut::*};
 use crate::charrange::CharRange;
+use crate::checksum::ChecksumRule;
+use crate::count::{self, Counters, Counts, UNROLL_LIMIT};
+use crate::custom::CustomNode;
+use crate::intern::ReRef;
+use crate::parse;
+use crate::parses;
+use crate::residue::{add_mod, mul_mod, Residues};
 
-#[derive(Eq, Hash, Debug)]
-pub struct Res(Vec<Rc<Re>>);
+#[derive(Clone, Eq, PartialEq, PartialOrd, Ord, Hash, Debug)]
+pub struct Res(Vec<ReRef>);
 
 #[derive(Clone, Eq, PartialEq, PartialOrd, Ord, Hash, Debug)]
 pub enum Re {
     Nul,
     Eps,
     Chars(Charset),
-    Neg(Rc<Re>),
+    Neg(ReRef),
     Alt(Res),
     And(Res),
-    Seq(Rc<Re>, Rc<Re>),
-    Star(Rc<Re>),
-    Fan(Rc<Re>, usize),
-    Lit(&'static str),
-    Moon(Rc<Re>, usize, usize),
-    Consider(Rc<Res>, usize, usize, usize),
+    Seq(ReRef, ReRef),
+    /// Every interleaving of one string from each operand.
+    Shuffle(Res),
+    Star(ReRef),
+    Fan(ReRef, usize),
+    /// Between a minimum and an optional maximum number of repetitions.
+    Repeat(ReRef, usize, Option<usize>),
+    Lit(LitStr),
+    Moon(ReRef, usize, usize),
+    /// Repeated `first`, `first + modulus`, `first + 2 * modulus`, ...
+    /// times. Holds the piece, `first` and the modulus. See
+    /// [`Re::repeat_mod`].
+    RepeatMod(ReRef, usize, usize),
+    /// The pieces read as the digits of a number, most significant first.
+    /// Holds the choices, the value so far, the accepted values and the
+    /// modulus.
+    Consider(Arc<Res>, u128, Residues, u128),
+    /// Like `Consider`, but reading the least significant digit first. Holds
+    /// the choices, the value to the left of the digits read so far, their
+    /// sum so far, the place value of the next digit, the accepted values and
+    /// the modulus.
+    ConsiderLsd(Arc<Res>, u128, u128, u128, Residues, u128),
+    /// A named capture group. It matches what its body matches and only
+    /// matters to [`Matcher::captures`](crate::Matcher::captures).
+    Group(Arc<str>, ReRef),
+    /// A zero-width assertion. See [`Lookaround`].
+    Look(Lookaround, ReRef),
+    /// A large repetition of the expression, counted instead of unrolled.
+    /// Each residual of the current piece is followed by a set of counts of
+    /// further pieces. See [`crate::count`].
+    Count(ReRef, Arc<Counters>),
+    /// A node defined outside this crate. See [`crate::custom`].
+    Custom(CustomNode),
+    /// A sum of per-piece contributions. Holds the rule, the number of pieces
+    /// read modulo the rule's period and, for each total number of pieces
+    /// modulo the period, the sum so far and the target. See
+    /// [`crate::checksum`].
+    Checksum(Arc<ChecksumRule>, usize, Vec<usize>, Vec<usize>),
+    /// Any string with between a minimum and an optional maximum number of
+    /// chars. See [`Re::length_between`].
+    Length(usize, Option<usize>),
+    /// Any string whose number of chars leaves the residue when divided by
+    /// the modulus. See [`Re::length_mod`].
+    LengthMod(usize, usize),
+    /// The strings that the head, then any number of the choices, then the
+    /// tail match in at least two ways. Holds the choices, the head and the
+    /// tail. See [`crate::parses`].
+    Ambiguous(Arc<Res>, ReRef, ReRef),
 }
 
-impl Res {
-    delegate! {
-        to self.0 {
-            pub fn iter(&self) -> std::slice::Iter<'_, Rc<Re>>;
-            pub fn len(&self) -> usize;
+/// The kinds of zero-width assertion.
+///
+/// An assertion checks the text matched by what it is concatenated with: a
+/// lookahead the expression to its right and a lookbehind the expression to
+/// its left, as far as the concatenation goes. So `x * lookahead(b) * y` is
+/// `x * ((b * !∅) & y)` and `x * lookbehind(b) * y` is `(x & (!∅ * b)) * y`.
+/// With nothing on its side, an assertion checks the empty string. Nothing
+/// outside the concatenation is looked at, so in `(lookahead(b) | x) * y` the
+/// lookahead only ever sees the empty string.
+///
+/// Nothing outside the match is looked at either. A lookbehind at the start
+/// of an expression sees the empty string even when a
+/// [`Searcher`](crate::Searcher) finds the expression in the middle of a text,
+/// and so does a lookahead at its end, so searching reports the same matches
+/// as matching each span on its own.
+#[derive(Clone, Copy, Eq, PartialEq, PartialOrd, Ord, Hash, Debug)]
+pub enum Lookaround {
+    Ahead,
+    NotAhead,
+    Behind,
+    NotBehind,
+}
+
+impl Lookaround {
+    pub(crate) fn is_ahead(self) -> bool {
+        matches!(self, Lookaround::Ahead | Lookaround::NotAhead)
+    }
+
+    /// The strings that pass the assertion on `re`.
+    fn language(self, re: &ReRef) -> ReRef {
+        let any = || ReRef::from(NUL.neg());
+        match self {
+            Lookaround::Ahead => Re::seq(re.clone(), any()),
+            Lookaround::NotAhead => Re::neg_rc(Re::seq(re.clone(), any())),
+            Lookaround::Behind => Re::seq(any(), re.clone()),
+            Lookaround::NotBehind => Re::neg_rc(Re::seq(any(), re.clone())),
+        }
+    }
+
+    /// The same assertion looking the other way.
+    pub fn reverse(self) -> Lookaround {
+        match self {
+            Lookaround::Ahead => Lookaround::Behind,
+            Lookaround::NotAhead => Lookaround::NotBehind,
+            Lookaround::Behind => Lookaround::Ahead,
+            Lookaround::NotBehind => Lookaround::NotAhead,
         }
     }
 }
 
-impl<'a> From<&'a Res> for &'a Vec<Rc<Re>> {
-    fn from(res: &'a Res) -> Self {
-        &res.0
+/// The remaining text of a literal: a suffix of a shared string, so that
+/// stepping past the first char does not copy the rest.
+#[derive(Clone)]
+pub struct LitStr {
+    buf: Arc<str>,
+    start: usize,
+}
+
+impl LitStr {
+    pub fn as_str(&self) -> &str {
+        &self.buf[self.start..]
+    }
+
+    /// The suffix starting `pos` bytes into this one, sharing its buffer.
+    fn tail(&self, pos: usize) -> LitStr {
+        assert!(self.as_str().is_char_boundary(pos));
+        LitStr {
+            buf: self.buf.clone(),
+            start: self.start + pos,
+        }
     }
 }
 
-impl From<Vec<Rc<Re>>> for Res {
-    fn from(vec: Vec<Rc<Re>>) -> Self {
-        Res(vec)
+impl Deref for LitStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<Arc<str>> for LitStr {
+    fn from(buf: Arc<str>) -> Self {
+        LitStr { buf, start: 0 }
+    }
+}
+
+impl From<String> for LitStr {
+    fn from(s: String) -> Self {
+        LitStr::from(Arc::<str>::from(s))
     }
 }
-impl Clone for Res {
-    fn clone(&self) -> Self {
-        Res(self.0.to_vec())
+
+impl From<&str> for LitStr {
+    fn from(s: &str) -> Self {
+        LitStr::from(Arc::<str>::from(s))
+    }
+}
+
+impl PartialEq for LitStr {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for LitStr {}
+
+impl Hash for LitStr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
     }
 }
 
-impl Ord for Res {
+impl Ord for LitStr {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.iter().cmp(other.iter())
+        self.as_str().cmp(other.as_str())
     }
 }
 
-impl PartialOrd for Res {
+impl PartialOrd for LitStr {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl PartialEq for Res {
-    fn eq(&self, other: &Self) -> bool {
-        self.cmp(other) == Ordering::Equal
+impl Debug for LitStr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(self.as_str(), f)
     }
 }
 
 impl Res {
-    fn derive(&self, ch: &char) -> (CharRange, Vec<Rc<Re>>) {
+    delegate! {
+        to self.0 {
+            pub fn iter(&self) -> std::slice::Iter<'_, ReRef>;
+            pub fn len(&self) -> usize;
+            pub fn is_empty(&self) -> bool;
+        }
+    }
+}
+
+impl<'a> From<&'a Res> for &'a Vec<ReRef> {
+    fn from(res: &'a Res) -> Self {
+        &res.0
+    }
+}
+
+impl From<Vec<ReRef>> for Res {
+    fn from(vec: Vec<ReRef>) -> Self {
+        Res(vec)
+    }
+}
+impl Res {
+    pub(crate) fn derive(&self, ch: &char) -> (CharRange, Vec<ReRef>) {
         let (ranges, res): (Vec<_>, Vec<_>) = self.iter().map(|re| re.derive(ch)).unzip();
         (
             ranges
@@ -86,7 +243,7 @@ impl Res {
 }
 
 impl Re {
-    pub fn nullable(self: &Self) -> bool {
+    pub fn nullable(&self) -> bool {
         match self {
             Re::Nul => false,
             Re::Eps => true,
@@ -95,15 +252,85 @@ impl Re {
             Re::Alt(res) => res.iter().any(|x| x.nullable()),
             Re::And(res) => res.iter().all(|x| x.nullable()),
             Re::Seq(a, b) => a.nullable() && b.nullable(),
+            Re::Shuffle(res) => res.iter().all(|x| x.nullable()),
             Re::Star(_) => true,
-            Re::Fan(_, _) => false,
+            Re::Fan(re, _) => re.nullable(),
+            Re::Repeat(re, min, _) => *min == 0 || re.nullable(),
             Re::Moon(_, phase, planet) => phase == planet,
-            Re::Consider(_, value, target, _) => value == target,
+            Re::RepeatMod(_, first, _) => *first == 0,
+            Re::Length(min, _) => *min == 0,
+            Re::LengthMod(residue, _) => *residue == 0,
+            Re::Consider(_, value, targets, _) => targets.contains(*value),
+            Re::ConsiderLsd(_, value, sum, place, targets, within) => {
+                targets.contains(add_mod(*sum, mul_mod(*value, *place, *within), *within))
+            }
             Re::Lit(s) => s.is_empty(),
+            Re::Group(_, re) => re.nullable(),
+            Re::Look(Lookaround::Ahead, re) | Re::Look(Lookaround::Behind, re) => re.nullable(),
+            Re::Look(_, re) => !re.nullable(),
+            Re::Count(re, counters) => counters.nullable(re),
+            Re::Custom(node) => node.nullable(),
+            Re::Checksum(_, pos, sums, targets) => sums[*pos] == targets[*pos],
+            // The empty string splits up in one way at most.
+            Re::Ambiguous(_, _, _) => false,
         }
     }
 
-    fn alt<T: IntoIterator<Item = Rc<Re>>>(parts: T) -> Rc<Re> {
+    /// The direct subexpressions of this node, in the order they match text
+    /// where there is one. Leaves, custom nodes included, have none.
+    pub(crate) fn children(&self) -> impl Iterator<Item = &ReRef> {
+        let children: Vec<&ReRef> = match self {
+            Re::Nul
+            | Re::Eps
+            | Re::Chars(_)
+            | Re::Lit(_)
+            | Re::Custom(_)
+            | Re::Length(_, _)
+            | Re::LengthMod(_, _) => vec![],
+            Re::Neg(a)
+            | Re::Star(a)
+            | Re::Fan(a, _)
+            | Re::Repeat(a, _, _)
+            | Re::Moon(a, _, _)
+            | Re::RepeatMod(a, _, _)
+            | Re::Group(_, a)
+            | Re::Look(_, a) => vec![a],
+            Re::Seq(a, b) => vec![a, b],
+            Re::Alt(res) | Re::And(res) | Re::Shuffle(res) => res.iter().collect(),
+            Re::Consider(res, ..) | Re::ConsiderLsd(res, ..) => res.iter().collect(),
+            Re::Checksum(rule, ..) => rule.choices.iter().collect(),
+            Re::Ambiguous(res, head, tail) => {
+                let mut children = vec![head];
+                children.extend(res.iter());
+                children.push(tail);
+                children
+            }
+            Re::Count(a, counters) => {
+                let mut children: Vec<_> = counters.iter().map(|(x, _)| x).collect();
+                children.push(a);
+                children
+            }
+        };
+        children.into_iter()
+    }
+
+    /// Whether a capture group occurs anywhere in this expression.
+    pub fn has_groups(&self) -> bool {
+        matches!(self, Re::Group(_, _)) || self.children().any(|x| x.has_groups())
+    }
+
+    /// Whether a `Count` occurs anywhere in this expression, which keeps it
+    /// out of a `Matcher`'s cached states.
+    pub fn has_counters(&self) -> bool {
+        matches!(self, Re::Count(_, _)) || self.children().any(|x| x.has_counters())
+    }
+
+    /// Builds an alternation whose operands are flattened, sorted and
+    /// deduplicated. Keeping `Alt` and `And` canonical modulo associativity,
+    /// commutativity and idempotence is what keeps the number of distinct
+    /// derivatives finite. `Count` operands of the same piece are merged for
+    /// the same reason.
+    pub(crate) fn alt<T: IntoIterator<Item = ReRef>>(parts: T) -> ReRef {
         let mut all = vec![];
         for x in parts {
             match &*x {
@@ -116,16 +343,18 @@ impl Re {
                 _ => all.push(x),
             }
         }
-        let mut seen = HashSet::new();
-        all.retain(|re| seen.insert(re.clone()));
+        count::merge_counts(&mut all);
+        all.sort();
+        all.dedup();
         match all.len() {
-            0 => Rc::from(NUL),
-            1 => return all.into_iter().next().expect("len = 1"),
-            _ => Rc::from(Re::Alt(Res(all))),
+            0 => ReRef::from(NUL),
+            1 => all.into_iter().next().expect("len = 1"),
+            _ => ReRef::from(Re::Alt(Res(all))),
         }
     }
 
-    fn and<T: IntoIterator<Item = Rc<Re>>>(parts: T) -> Rc<Re> {
+    /// Builds an intersection, canonicalized the same way as [`Re::alt`].
+    pub(crate) fn and<T: IntoIterator<Item = ReRef>>(parts: T) -> ReRef {
         let mut all = vec![];
         for x in parts {
             match &*x {
@@ -138,49 +367,149 @@ impl Re {
                 _ => all.push(x),
             }
         }
-        let mut seen = HashSet::new();
-        all.retain(|re| seen.insert(re.clone()));
+        if !merge_lengths(&mut all) {
+            return ReRef::from(Re::Nul);
+        }
+        all.sort();
+        all.dedup();
         match all.len() {
-            0 => Rc::from(NUL.neg()),
-            1 => return all.into_iter().next().expect("len = 1"),
-            _ => Rc::from(Re::And(Res(all))),
+            0 => ReRef::from(NUL.neg()),
+            1 => all.into_iter().next().expect("len = 1"),
+            _ => ReRef::from(Re::And(Res(all))),
         }
     }
 
-    fn seq<A: Into<Rc<Re>>, B: Into<Rc<Re>>>(a: A, b: B) -> Rc<Re> {
+    /// Builds a shuffle whose operands are flattened and sorted. Unlike
+    /// alternation, repeated operands are kept, since `a` interleaved with
+    /// `a` is `aa`. Empty strings drop out and an empty operand empties the
+    /// whole shuffle.
+    pub(crate) fn shuffle_rc<T: IntoIterator<Item = ReRef>>(parts: T) -> ReRef {
+        let mut all = vec![];
+        for x in parts {
+            match &*x {
+                Re::Shuffle(res) => all.extend(res.iter().cloned()),
+                Re::Eps => (),
+                Re::Nul => return x,
+                _ => all.push(x),
+            }
+        }
+        all.sort();
+        match all.len() {
+            0 => ReRef::from(Eps),
+            1 => all.into_iter().next().expect("len = 1"),
+            _ => ReRef::from(Re::Shuffle(Res(all))),
+        }
+    }
+
+    /// Builds a concatenation, right-nested. An assertion next to another
+    /// expression is replaced by an intersection with it here, so `Look` only
+    /// survives where nothing is on its side yet: lookahead at the end of a
+    /// concatenation and lookbehind at its start.
+    pub(crate) fn seq<A: Into<ReRef>, B: Into<ReRef>>(a: A, b: B) -> ReRef {
         let a = a.into();
         let b = b.into();
-        match &*a {
-            Re::Nul => Rc::from(Re::Nul),
-            Re::Eps => b,
-            Re::Seq(x, y) => Self::seq(x.clone(), Self::seq(y.clone(), b)),
-            _ => match &*b {
-                Re::Nul => Rc::from(Re::Nul),
-                Re::Eps => a,
-                _ => Rc::from(Re::Seq(a, b)),
-            },
+        match (&*a, &*b) {
+            (Re::Nul, _) | (_, Re::Nul) => ReRef::from(Re::Nul),
+            (Re::Eps, _) => b,
+            (_, Re::Eps) => a,
+            (Re::Look(look, x), _) if look.is_ahead() => Self::and(vec![look.language(x), b]),
+            (_, Re::Look(look, x)) if !look.is_ahead() => Self::and(vec![a, look.language(x)]),
+            (_, Re::Seq(first, rest)) if matches!(&**first, Re::Look(look, _) if !look.is_ahead()) => {
+                Self::seq(Self::seq(a, first.clone()), rest.clone())
+            }
+            (Re::Seq(x, y), _) => Self::seq(x.clone(), Self::seq(y.clone(), b)),
+            _ => ReRef::from(Re::Seq(a, b)),
         }
     }
 
-    fn neg_rc<T: Into<Rc<Re>>>(re: T) -> Rc<Re> {
+    /// Builds a repetition, using simpler nodes for the counts that have
+    /// them.
+    pub(crate) fn repeat_rc<T: Into<ReRef>>(re: T, min: usize, max: Option<usize>) -> ReRef {
+        let re = re.into();
+        match (min, max) {
+            (min, Some(max)) if min > max => ReRef::from(Nul),
+            (_, Some(0)) => ReRef::from(Eps),
+            (0, None) => ReRef::from(Star(re)),
+            (1, Some(1)) => re,
+            _ => ReRef::from(Repeat(re, min, max)),
+        }
+    }
+
+    /// `re` repeated `first`, `first + modulus`, ... times. If `re` matches
+    /// the empty string, any number of pieces can be padded out with empty
+    /// ones, so that is just `re*`.
+    pub(crate) fn repeat_mod_rc(re: ReRef, first: usize, modulus: usize) -> ReRef {
+        if re.nullable() {
+            return ReRef::from(Star(re));
+        }
+        ReRef::from(RepeatMod(re, first, modulus))
+    }
+
+    /// The smallest count that is at least `min_count` and leaves `residue`
+    /// when divided by `modulus`, or `None` if it does not fit in a `usize`.
+    pub(crate) fn first_count(residue: usize, modulus: usize, min_count: usize) -> Option<usize> {
+        let (residue, below) = (residue % modulus, min_count % modulus);
+        let offset = if residue >= below {
+            residue - below
+        } else {
+            modulus - (below - residue)
+        };
+        min_count.checked_add(offset)
+    }
+
+    /// Any string of `min` to `max` chars. Every char derives it the same
+    /// way, so it never splits the ranges of whatever it is intersected with.
+    pub(crate) fn length_rc(min: usize, max: Option<usize>) -> ReRef {
+        match max {
+            Some(max) if max < min => ReRef::from(Nul),
+            None if min == 0 => ReRef::from(NUL.neg()),
+            _ => ReRef::from(Length(min, max)),
+        }
+    }
+
+    pub(crate) fn length_mod_rc(residue: usize, modulus: usize) -> ReRef {
+        if modulus == 1 {
+            return ReRef::from(NUL.neg());
+        }
+        ReRef::from(LengthMod(residue % modulus, modulus))
+    }
+
+    /// Any string of at least `min` and at most `max` chars, or with no
+    /// upper bound if `max` is `None`.
+    pub fn length_between(min: usize, max: Option<usize>) -> Re {
+        Self::length_rc(min, max).as_ref().clone()
+    }
+
+    /// Any string whose number of chars leaves `residue` when divided by
+    /// `modulus`.
+    ///
+    /// # Panics
+    ///
+    /// If `modulus` is zero.
+    pub fn length_mod(residue: usize, modulus: usize) -> Re {
+        assert!(modulus > 0, "the modulus must not be zero");
+        Self::length_mod_rc(residue, modulus).as_ref().clone()
+    }
+
+    pub(crate) fn neg_rc<T: Into<ReRef>>(re: T) -> ReRef {
         let re = re.into();
         match &*re {
             Re::Neg(re) => re.clone(),
-            _ => Rc::from(Re::Neg(re)),
+            _ => ReRef::from(Re::Neg(re)),
         }
     }
 
-    pub fn derive(&self, ch: &char) -> (CharRange, Rc<Re>) {
+    pub fn derive(&self, ch: &char) -> (CharRange, ReRef) {
         use crate::Re::*;
         match self {
-            Nul | Eps => (CharRange::all(), Rc::from(Nul)),
+            Nul | Eps => (CharRange::all(), ReRef::from(Nul)),
             Chars(set) => match set.get_in_or_out(ch) {
-                In(range) => (range.clone(), Rc::from(Eps)),
-                Out(range) => (range, Rc::from(Nul)),
+                In(range) => (range.clone(), ReRef::from(Eps)),
+                Out(range) => (range, ReRef::from(Nul)),
             },
             Lit(s) => {
                 if s.is_empty() {
-                    return (CharRange::all(), Rc::from(Nul));
+                    (CharRange::all(), ReRef::from(Nul))
                 } else {
                     let mut indices = s.char_indices();
                     let (_, first) = indices.next().expect("checked non-empty");
@@ -188,13 +517,13 @@ impl Re {
                         (
                             CharRange::from(*ch),
                             if let Some((pos, _)) = indices.next() {
-                                Rc::from(Lit(&s[pos..]))
+                                ReRef::from(Lit(s.tail(pos)))
                             } else {
-                                Rc::from(EPS)
+                                ReRef::from(EPS)
                             },
                         )
                     } else if *ch < first {
-                        (CharRange::end_at(first), Rc::from(NUL))
+                        (CharRange::end_at(first), ReRef::from(NUL))
                     } else {
                         (
                             CharRange::start_from(
@@ -202,18 +531,28 @@ impl Re {
                                     "we can't be at the end of the range because first < ch",
                                 ),
                             ),
-                            Rc::from(NUL),
+                            ReRef::from(NUL),
                         )
                     }
                 }
             }
             Alt(res) => {
                 let (range, res) = res.derive(ch);
-                (range, Self::alt(res.into_iter()))
+                (range, Self::alt(res))
             }
             And(res) => {
                 let (range, res) = res.derive(ch);
-                (range, Self::and(res.into_iter()))
+                (range, Self::and(res))
+            }
+            // The next char comes from any one of the operands.
+            Shuffle(res) => {
+                let (range, derived) = res.derive(ch);
+                let parts = derived.into_iter().enumerate().map(|(i, x)| {
+                    let mut parts = res.0.clone();
+                    parts[i] = x;
+                    Self::shuffle_rc(parts)
+                });
+                (range, Self::alt(parts.collect::<Vec<_>>()))
             }
             Neg(x) => {
                 let (range, x) = x.derive(ch);
@@ -224,7 +563,7 @@ impl Re {
                 let aprime_b = Self::seq(aprime, b.clone());
                 if a.nullable() {
                     let (r1, bprime) = b.derive(ch);
-                    (&r0 & &r1, Self::alt(vec![aprime_b, bprime].into_iter()))
+                    (&r0 & &r1, Self::alt(vec![aprime_b, bprime]))
                 } else {
                     (r0, aprime_b)
                 }
@@ -233,42 +572,178 @@ impl Re {
                 let (range, aprime) = a.derive(ch);
                 (range, Self::seq(aprime, self.clone()))
             }
-            Fan(a, count) => {
-                let next = if *count > 2usize {
-                    Rc::from(Fan(a.clone(), count - 1))
-                } else {
-                    a.clone()
-                };
+            Fan(a, count) if *count > UNROLL_LIMIT => {
+                count::counted(a.clone(), Counts::exactly(*count)).derive(ch)
+            }
+            Fan(a, _) => {
                 let (range, aprime) = a.derive(ch);
-                (range, Self::seq(aprime, next))
+                (range, Self::seq(aprime, self.repeat_next()))
             }
-            Moon(a, phase, planet) => {
+            Repeat(a, min, max) if max.unwrap_or(*min) > UNROLL_LIMIT => {
+                count::counted(a.clone(), Counts::between(*min, *max)).derive(ch)
+            }
+            Repeat(a, _, _) => {
+                // Counting down works even for nullable `a`: then `a^k`
+                // includes `a^(k-1)`, so only the longest count matters.
                 let (range, aprime) = a.derive(ch);
-                let next_phase = if phase >= planet {
-                    phase - planet + 1
-                } else {
-                    phase + 1
-                };
-                (
-                    range,
-                    Self::seq(aprime, Rc::from(Re::Moon(a.clone(), next_phase, *planet))),
-                )
+                (range, Self::seq(aprime, self.repeat_next()))
+            }
+            Moon(a, _, _) => {
+                let (range, aprime) = a.derive(ch);
+                (range, Self::seq(aprime, self.repeat_next()))
+            }
+            Length(_, Some(0)) => (CharRange::all(), ReRef::from(Nul)),
+            Length(min, max) => (
+                CharRange::all(),
+                Self::length_rc(min.saturating_sub(1), max.map(|max| max - 1)),
+            ),
+            LengthMod(residue, modulus) => (
+                CharRange::all(),
+                Self::length_mod_rc(residue.checked_sub(1).unwrap_or(modulus - 1), *modulus),
+            ),
+            RepeatMod(a, _, _) => {
+                let (range, aprime) = a.derive(ch);
+                (range, Self::seq(aprime, self.repeat_next()))
             }
-            Consider(choices, value, target, within) => {
-                let mut index = 0;
+            Consider(choices, ..) | ConsiderLsd(choices, ..) => {
                 let (range, derived) = choices.as_ref().derive(ch);
                 (
                     range,
-                    Self::alt(derived.into_iter().map(|re| {
-                        let next_value = (value * choices.len() + index) % within;
-                        index += 1;
-                        Self::seq(
-                            re,
-                            Rc::from(Re::Consider(choices.clone(), next_value, *target, *within)),
-                        )
-                    })),
+                    Self::alt(
+                        derived
+                            .into_iter()
+                            .enumerate()
+                            .map(|(index, re)| Self::seq(re, self.consider_next(index))),
+                    ),
                 )
             }
+            Group(_, a) => a.derive(ch),
+            Look(_, _) => (CharRange::all(), ReRef::from(Nul)),
+            Count(a, counters) => count::derive(a, counters, ch),
+            Custom(node) => node.derive(ch),
+            Checksum(rule, pos, sums, targets) => rule.derive(*pos, sums, targets, ch),
+            Ambiguous(choices, head, tail) => parses::derive(choices, head, tail, ch),
+        }
+    }
+
+    /// What a `Consider` or `ConsiderLsd` becomes after reading the choice at
+    /// `index`.
+    pub(crate) fn consider_next(&self, index: usize) -> ReRef {
+        match self {
+            Consider(choices, value, targets, within) => {
+                let base = choices.len() as u128 % within;
+                let value = add_mod(
+                    mul_mod(*value, base, *within),
+                    index as u128 % within,
+                    *within,
+                );
+                ReRef::from(Consider(choices.clone(), value, targets.clone(), *within))
+            }
+            ConsiderLsd(choices, value, sum, place, targets, within) => {
+                let base = choices.len() as u128 % within;
+                let digit = mul_mod(index as u128 % within, *place, *within);
+                ReRef::from(ConsiderLsd(
+                    choices.clone(),
+                    *value,
+                    add_mod(*sum, digit, *within),
+                    mul_mod(*place, base, *within),
+                    targets.clone(),
+                    *within,
+                ))
+            }
+            _ => panic!("{} is not a Consider", self),
+        }
+    }
+
+    /// What a `Star`, `Fan`, `Repeat`, `Moon` or `RepeatMod` has left to
+    /// match after one piece.
+    pub(crate) fn repeat_next(&self) -> ReRef {
+        match self {
+            Star(_) => ReRef::from(self.clone()),
+            Fan(a, count) if *count > 2 => ReRef::from(Fan(a.clone(), count - 1)),
+            Fan(a, _) => a.clone(),
+            // Only reachable for a `Repeat` built by hand, as `repeat_rc`
+            // makes `{0}` an `Eps`.
+            Repeat(_, _, Some(0)) => ReRef::from(Nul),
+            Repeat(a, min, max) => {
+                Self::repeat_rc(a.clone(), min.saturating_sub(1), max.map(|max| max - 1))
+            }
+            Moon(a, phase, planet) => {
+                let phase = if phase >= planet {
+                    phase - planet + 1
+                } else {
+                    phase + 1
+                };
+                ReRef::from(Moon(a.clone(), phase, *planet))
+            }
+            RepeatMod(a, first, modulus) => {
+                let first = first.checked_sub(1).unwrap_or(modulus - 1);
+                Self::repeat_mod_rc(a.clone(), first, *modulus)
+            }
+            _ => panic!("{} is not a repetition", self),
+        }
+    }
+
+    /// Rebuilds this node with every child replaced by `f(child)`. Leaves,
+    /// custom nodes included, are returned unchanged.
+    pub(crate) fn map_children<F: FnMut(&ReRef) -> ReRef>(&self, mut f: F) -> ReRef {
+        fn map_res<F: FnMut(&ReRef) -> ReRef>(res: &Res, f: F) -> Arc<Res> {
+            Arc::new(Res(res.iter().map(f).collect()))
+        }
+        match self {
+            Nul | Eps | Chars(_) | Lit(_) | Custom(_) | Length(_, _) | LengthMod(_, _) => {
+                ReRef::from(self.clone())
+            }
+            Neg(x) => Self::neg_rc(f(x)),
+            Alt(res) => Self::alt(res.iter().map(f).collect::<Vec<_>>()),
+            And(res) => Self::and(res.iter().map(f).collect::<Vec<_>>()),
+            Shuffle(res) => Self::shuffle_rc(res.iter().map(f).collect::<Vec<_>>()),
+            Seq(a, b) => Self::seq(f(a), f(b)),
+            Star(a) => ReRef::from(Star(f(a))),
+            Fan(a, count) => ReRef::from(Fan(f(a), *count)),
+            Repeat(a, min, max) => ReRef::from(Repeat(f(a), *min, *max)),
+            Moon(a, phase, planet) => ReRef::from(Moon(f(a), *phase, *planet)),
+            RepeatMod(a, first, modulus) => Self::repeat_mod_rc(f(a), *first, *modulus),
+            Group(name, a) => ReRef::from(Group(name.clone(), f(a))),
+            Look(look, a) => ReRef::from(Look(*look, f(a))),
+            Checksum(rule, pos, sums, targets) => {
+                let rule = ChecksumRule {
+                    choices: Res(rule.choices.iter().map(f).collect()),
+                    ..rule.as_ref().clone()
+                };
+                ReRef::from(Checksum(
+                    Arc::new(rule),
+                    *pos,
+                    sums.clone(),
+                    targets.clone(),
+                ))
+            }
+            Count(a, counters) => {
+                let a = f(a);
+                let entries: Vec<_> = counters
+                    .iter()
+                    .map(|(x, counts)| (f(x), counts.clone()))
+                    .collect();
+                count::count_rc(a, entries)
+            }
+            Consider(choices, value, targets, within) => ReRef::from(Consider(
+                map_res(choices, f),
+                *value,
+                targets.clone(),
+                *within,
+            )),
+            ConsiderLsd(choices, value, sum, place, targets, within) => ReRef::from(ConsiderLsd(
+                map_res(choices, f),
+                *value,
+                *sum,
+                *place,
+                targets.clone(),
+                *within,
+            )),
+            Ambiguous(choices, head, tail) => {
+                let (head, tail) = (f(head), f(tail));
+                parses::ambiguous(map_res(choices, f), head, tail)
+            }
         }
     }
 }
@@ -278,14 +753,10 @@ use Re::*;
 impl BitAnd<Re> for Re {
     type Output = Re;
     fn bitand(self, other: Re) -> Self::Output {
-        match &self {
-            Chars(set1) => match &other {
-                Chars(set2) => return Chars(set1 & set2),
-                _ => (),
-            },
-            _ => (),
+        if let (Chars(set1), Chars(set2)) = (&self, &other) {
+            return Chars(set1 & set2);
         }
-        Re::and(vec![Rc::from(self), Rc::from(other)])
+        Re::and(vec![ReRef::from(self), ReRef::from(other)])
             .as_ref()
             .clone()
     }
@@ -294,14 +765,10 @@ impl BitAnd<Re> for Re {
 impl BitOr<Re> for Re {
     type Output = Re;
     fn bitor(self, other: Re) -> Self::Output {
-        match &self {
-            Chars(set1) => match &other {
-                Chars(set2) => return Chars(set1 | set2),
-                _ => (),
-            },
-            _ => (),
+        if let (Chars(set1), Chars(set2)) = (&self, &other) {
+            return Chars(set1 | set2);
         }
-        Re::alt(vec![Rc::from(self), Rc::from(other)])
+        Re::alt(vec![ReRef::from(self), ReRef::from(other)])
             .as_ref()
             .clone()
     }
@@ -323,8 +790,8 @@ impl<T: Into<CharRange>> From<T> for Re {
     }
 }
 
-pub fn sundae(s: &'static str) -> Re {
-    Re::from(Lit(s))
+pub fn sundae<S: Into<LitStr>>(s: S) -> Re {
+    Lit(s.into())
 }
 
 pub fn cheese<T: Into<CharRange>>(rng: T) -> Re {
@@ -337,45 +804,821 @@ pub fn toppings(chars: &str) -> Re {
 
 impl Re {
     pub fn star(self) -> Self {
-        Re::Star(Rc::from(self))
+        Re::Star(ReRef::from(self))
     }
 
     pub fn sun(self) -> Self {
-        return self.star() & EPS.neg();
+        self.star() & EPS.neg()
     }
 
     pub fn fickle(self) -> Self {
-        return self | EPS;
+        self | EPS
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn neg(self) -> Self {
-        Re::Neg(Rc::from(self))
+        Re::Neg(ReRef::from(self))
+    }
+
+    /// Matches every interleaving of a string this matches with a string
+    /// `other` matches.
+    pub fn shuffle(self, other: Re) -> Self {
+        Self::shuffle_rc(vec![ReRef::from(self), ReRef::from(other)])
+            .as_ref()
+            .clone()
     }
 
     pub fn moon_phase(self, phase: usize, planet: usize) -> Self {
-        Re::Moon(Rc::from(self), phase, planet)
+        Re::Moon(ReRef::from(self), phase, planet)
     }
 
     pub fn moon(self, planet: usize) -> Self {
         self.moon_phase(1, planet)
     }
 
+    /// Repeats this expression `k` times for any `k` that is at least
+    /// `min_count` and leaves `residue` when divided by `modulus`.
+    ///
+    /// `moon_phase(phase, planet)` is the same as `repeat_mod(k % planet,
+    /// planet, k)`, where `k` is the number of pieces it takes the phase to
+    /// reach the planet, except that a `Moon` never skips an empty piece.
+    ///
+    /// # Panics
+    ///
+    /// If `modulus` is zero, or if the smallest such `k` does not fit in a
+    /// `usize`.
+    pub fn repeat_mod(self, residue: usize, modulus: usize, min_count: usize) -> Self {
+        assert!(modulus > 0, "the modulus must not be zero");
+        let first = Self::first_count(residue, modulus, min_count)
+            .expect("the first count must fit in a usize");
+        Self::repeat_mod_rc(ReRef::from(self), first, modulus)
+            .as_ref()
+            .clone()
+    }
+
+    /// Wraps this expression in a capture group called `name`.
+    ///
+    /// # Panics
+    ///
+    /// If `name` is not a run of letters, digits and `_`, as the text syntax
+    /// could not print it.
+    pub fn capture<S: Into<Arc<str>>>(self, name: S) -> Self {
+        let name = name.into();
+        assert!(
+            parse::is_group_name(&name),
+            "{:?} is not a valid group name",
+            name
+        );
+        Group(name, ReRef::from(self))
+    }
+
+    /// Matches between `min` and `max` repetitions, or at least `min` when
+    /// `max` is `None`.
+    pub fn repeat(self, min: usize, max: Option<usize>) -> Self {
+        Re::repeat_rc(self, min, max).as_ref().clone()
+    }
+
+    /// Matches `count` or more repetitions.
+    pub fn at_least(self, count: usize) -> Self {
+        self.repeat(count, None)
+    }
+
     pub fn fan(self, count: usize) -> Self {
         if count == 0 {
             EPS
         } else if count == 1 {
             self
         } else {
-            Fan(Rc::from(self), count)
+            Fan(ReRef::from(self), count)
         }
     }
 }
 
 pub fn consider<I: IntoIterator<Item = Re>>(re: I, target: usize, within: usize) -> Re {
+    consider_any(re, vec![target as u128], within as u128)
+}
+
+/// Like [`consider`], but accepting any of `targets`, with a modulus that
+/// may be as large as `u128` allows.
+pub fn consider_any<I, T>(re: I, targets: T, within: u128) -> Re
+where
+    I: IntoIterator<Item = Re>,
+    T: IntoIterator<Item = u128>,
+{
+    let targets = Residues::new(targets, within);
+    if targets.is_empty(within) {
+        return NUL;
+    }
     Re::Consider(
-        Rc::from(Res(re.into_iter().map(Rc::from).collect())),
-        0usize,
-        target,
+        Arc::new(Res(re.into_iter().map(ReRef::from).collect())),
+        0,
+        targets,
         within,
     )
 }
+
+/// Asserts that the text to the right starts with a match of `re`. See
+/// [`Lookaround`].
+pub fn lookahead(re: Re) -> Re {
+    Look(Lookaround::Ahead, ReRef::from(re))
+}
+
+/// Asserts that the text to the right does not start with a match of `re`.
+pub fn negative_lookahead(re: Re) -> Re {
+    Look(Lookaround::NotAhead, ReRef::from(re))
+}
+
+/// Asserts that the text to the left ends with a match of `re`.
+pub fn lookbehind(re: Re) -> Re {
+    Look(Lookaround::Behind, ReRef::from(re))
+}
+
+/// Asserts that the text to the left does not end with a match of `re`.
+pub fn negative_lookbehind(re: Re) -> Re {
+    Look(Lookaround::NotBehind, ReRef::from(re))
+}
+
+/// Like [`consider`], but with the first piece as the least significant
+/// digit. Matches the reverses of the strings `consider` matches when each
+/// choice is reversed too.
+pub fn consider_lsd<I: IntoIterator<Item = Re>>(re: I, target: usize, within: usize) -> Re {
+    consider_lsd_any(re, vec![target as u128], within as u128)
+}
+
+/// Like [`consider_any`], but with the first piece as the least significant
+/// digit.
+pub fn consider_lsd_any<I, T>(re: I, targets: T, within: u128) -> Re
+where
+    I: IntoIterator<Item = Re>,
+    T: IntoIterator<Item = u128>,
+{
+    let targets = Residues::new(targets, within);
+    if targets.is_empty(within) {
+        return NUL;
+    }
+    Re::ConsiderLsd(
+        Arc::new(Res(re.into_iter().map(ReRef::from).collect())),
+        0,
+        0,
+        1 % within,
+        targets,
+        within,
+    )
+}
+
+/// Replaces the `Length` operands of an intersection with a single one, and
+/// checks that no two `LengthMod` operands with the same modulus disagree.
+/// Returns `false` if the intersection is empty.
+fn merge_lengths(all: &mut Vec<ReRef>) -> bool {
+    let mut bounds: Option<(usize, Option<usize>)> = None;
+    let mut residues: Vec<(usize, usize)> = vec![];
+    let mut disjoint = false;
+    all.retain(|x| match **x {
+        Length(min, max) => {
+            bounds = Some(match bounds {
+                Some((lo, hi)) => (lo.max(min), hi.into_iter().chain(max).min()),
+                None => (min, max),
+            });
+            false
+        }
+        LengthMod(residue, modulus) => {
+            match residues.iter().find(|&&(_, m)| m == modulus) {
+                Some(&(r, _)) => disjoint |= r != residue,
+                None => residues.push((residue, modulus)),
+            }
+            true
+        }
+        _ => true,
+    });
+    if let Some((min, max)) = bounds {
+        let length = Re::length_rc(min, max);
+        match &*length {
+            Nul => return false,
+            Length(_, _) => all.push(length),
+            _ => (),
+        }
+    }
+    !disjoint
+}
+
+/// Writes the accepted values of a `Consider` after its running state: as a
+/// single number in the same list when there is one, and as a list of its
+/// own otherwise, marked with a `!` if the values are the ones left out.
+fn write_targets(
+    f: &mut Formatter<'_>,
+    state: &[u128],
+    targets: &Residues,
+    within: u128,
+) -> fmt::Result {
+    let join = |numbers: &[u128]| {
+        numbers
+            .iter()
+            .map(|n| n.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    match targets.listed() {
+        [target] if !targets.is_complement() => {
+            let numbers: Vec<u128> = state.iter().cloned().chain(vec![*target, within]).collect();
+            write!(f, "; {})", join(&numbers))
+        }
+        listed => write!(
+            f,
+            "; {}; {}{}; {})",
+            join(state),
+            if targets.is_complement() { "!" } else { "" },
+            join(listed),
+            within
+        ),
+    }
+}
+
+/// How tightly each kind of node binds, loosest first. A node printed where a
+/// tighter binding is required gets parenthesized.
+const PREC_ALT: u8 = 0;
+const PREC_AND: u8 = 1;
+const PREC_SEQ: u8 = 2;
+const PREC_NEG: u8 = 3;
+const PREC_POSTFIX: u8 = 4;
+const PREC_ATOM: u8 = 5;
+
+fn write_char(f: &mut Formatter<'_>, ch: char, specials: &str) -> fmt::Result {
+    match ch {
+        '\n' => write!(f, "\\n"),
+        '\r' => write!(f, "\\r"),
+        '\t' => write!(f, "\\t"),
+        '\0' => write!(f, "\\0"),
+        _ if ch.is_control() => write!(f, "\\u{{{:x}}}", ch as u32),
+        _ if ch == '\\' || specials.contains(ch) => write!(f, "\\{}", ch),
+        _ => write!(f, "{}", ch),
+    }
+}
+
+fn write_joined<'a, I: IntoIterator<Item = &'a ReRef>>(
+    f: &mut Formatter<'_>,
+    res: I,
+    sep: &str,
+    prec: u8,
+) -> fmt::Result {
+    for (i, re) in res.into_iter().enumerate() {
+        if i > 0 {
+            write!(f, "{}", sep)?;
+        }
+        re.fmt_prec(f, prec)?;
+    }
+    Ok(())
+}
+
+impl Re {
+    fn prec(&self) -> u8 {
+        match self {
+            Alt(_) => PREC_ALT,
+            And(_) => PREC_AND,
+            Seq(_, _) => PREC_SEQ,
+            Neg(_) => PREC_NEG,
+            Star(_) | Fan(_, _) | Repeat(_, _, _) => PREC_POSTFIX,
+            _ => PREC_ATOM,
+        }
+    }
+
+    fn fmt_prec(&self, f: &mut Formatter<'_>, min: u8) -> fmt::Result {
+        if self.prec() < min {
+            write!(f, "(")?;
+            self.fmt_prec(f, PREC_ALT)?;
+            return write!(f, ")");
+        }
+        match self {
+            Nul => write!(f, "∅"),
+            Eps => write!(f, "ε"),
+            Chars(set) => {
+                let mut ranges = set.ranges();
+                match (ranges.next(), ranges.next()) {
+                    (Some(range), None) if *range == CharRange::all() => write!(f, "."),
+                    (Some(range), None) if range.len() == 1 => {
+                        write_char(f, range.start(), "|&!*+?{}()[].\"%ε∅,;")
+                    }
+                    _ => {
+                        write!(f, "[")?;
+                        for range in set.ranges() {
+                            write_char(f, range.start(), "]-^")?;
+                            if range.len() > 1 {
+                                write!(f, "-")?;
+                                write_char(f, range.last(), "]-^")?;
+                            }
+                        }
+                        write!(f, "]")
+                    }
+                }
+            }
+            Lit(s) => {
+                write!(f, "\"")?;
+                for ch in s.chars() {
+                    write_char(f, ch, "\"")?;
+                }
+                write!(f, "\"")
+            }
+            Neg(re) => {
+                write!(f, "!")?;
+                re.fmt_prec(f, PREC_NEG)
+            }
+            Alt(res) => write_joined(f, res.iter(), "|", PREC_AND),
+            And(res) => write_joined(f, res.iter(), "&", PREC_SEQ),
+            Seq(a, b) => {
+                a.fmt_prec(f, PREC_NEG)?;
+                b.fmt_prec(f, PREC_SEQ)
+            }
+            Star(re) => {
+                re.fmt_prec(f, PREC_POSTFIX)?;
+                write!(f, "*")
+            }
+            Fan(re, count) => {
+                re.fmt_prec(f, PREC_POSTFIX)?;
+                write!(f, "{{{}}}", count)
+            }
+            Repeat(re, min, max) => {
+                re.fmt_prec(f, PREC_POSTFIX)?;
+                match max {
+                    Some(max) => write!(f, "{{{},{}}}", min, max),
+                    None => write!(f, "{{{},}}", min),
+                }
+            }
+            Moon(re, phase, planet) => {
+                write!(f, "%moon(")?;
+                re.fmt_prec(f, PREC_ALT)?;
+                write!(f, "; {}, {})", phase, planet)
+            }
+            Length(min, max) => match max {
+                Some(max) if max == min => write!(f, "%length({})", min),
+                Some(max) => write!(f, "%length({}-{})", min, max),
+                None => write!(f, "%length({}-)", min),
+            },
+            LengthMod(residue, modulus) => write!(f, "%length_mod({}, {})", residue, modulus),
+            Shuffle(res) => {
+                write!(f, "%shuffle(")?;
+                write_joined(f, res.iter(), ",", PREC_ALT)?;
+                write!(f, ")")
+            }
+            RepeatMod(re, first, modulus) => {
+                write!(f, "%repeat_mod(")?;
+                re.fmt_prec(f, PREC_ALT)?;
+                write!(f, "; {}, {}, {})", first % modulus, modulus, first)
+            }
+            Consider(choices, value, targets, within) => {
+                write!(f, "%consider(")?;
+                write_joined(f, choices.iter(), ",", PREC_ALT)?;
+                write_targets(f, &[*value], targets, *within)
+            }
+            Look(look, re) => {
+                let open = match look {
+                    Lookaround::Ahead => "(?=",
+                    Lookaround::NotAhead => "(?!",
+                    Lookaround::Behind => "(?<=",
+                    Lookaround::NotBehind => "(?<!",
+                };
+                write!(f, "{}", open)?;
+                re.fmt_prec(f, PREC_ALT)?;
+                write!(f, ")")
+            }
+            Group(name, re) => {
+                write!(f, "(?<{}>", name)?;
+                re.fmt_prec(f, PREC_ALT)?;
+                write!(f, ")")
+            }
+            ConsiderLsd(choices, value, sum, place, targets, within) => {
+                write!(f, "%consider_lsd(")?;
+                write_joined(f, choices.iter(), ",", PREC_ALT)?;
+                write_targets(f, &[*value, *sum, *place], targets, *within)
+            }
+            Custom(node) => write!(f, "{}", node),
+            Checksum(rule, pos, sums, targets) => {
+                write!(f, "%checksum(")?;
+                write_joined(f, rule.choices.iter(), ",", PREC_ALT)?;
+                write!(
+                    f,
+                    "; {}, {}, {}",
+                    rule.within, rule.from_right as usize, pos
+                )?;
+                for numbers in rule.table.iter().chain(vec![sums, targets]) {
+                    write!(f, ";")?;
+                    for (i, n) in numbers.iter().enumerate() {
+                        write!(f, "{} {}", if i > 0 { "," } else { "" }, n)?;
+                    }
+                }
+                write!(f, ")")
+            }
+            Count(re, counters) => {
+                write!(f, "%count(")?;
+                re.fmt_prec(f, PREC_ALT)?;
+                for (x, _) in counters.iter() {
+                    write!(f, ",")?;
+                    x.fmt_prec(f, PREC_ALT)?;
+                }
+                for (_, counts) in counters.iter() {
+                    write!(f, ";")?;
+                    for (i, (lo, hi)) in counts.ranges().enumerate() {
+                        if i > 0 {
+                            write!(f, ",")?;
+                        }
+                        match hi {
+                            Some(hi) if hi == lo => write!(f, " {}", lo)?,
+                            Some(hi) => write!(f, " {}-{}", lo, hi)?,
+                            None => write!(f, " {}-", lo)?,
+                        }
+                    }
+                }
+                write!(f, ")")
+            }
+            Ambiguous(choices, head, tail) => {
+                write!(f, "%ambiguous(")?;
+                write_joined(
+                    f,
+                    vec![head, tail].into_iter().chain(choices.iter()),
+                    ",",
+                    PREC_ALT,
+                )?;
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// Prints in the syntax understood by [`Re::parse`], so that parsing the
+/// output gives back an equal `Re`, unless it contains a custom node: that
+/// prints whatever its own `Display` prints, which the parser cannot read (see
+/// [`crate::custom`]).
+impl Display for Re {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.fmt_prec(f, PREC_ALT)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compile;
+    use crate::testing::strings;
+
+    fn round_trip(re: &Re) {
+        let printed = re.to_string();
+        let parsed = Re::parse(&printed).unwrap_or_else(|e| panic!("{}: {}", printed, e));
+        assert_eq!(&parsed, re, "{}", printed);
+    }
+
+    /// Round trips `re` and every derivative along `inputs`.
+    fn round_trip_derivatives(re: Re, inputs: &[&str]) {
+        round_trip(&re);
+        for input in inputs {
+            let mut re = ReRef::from(re.clone());
+            for ch in input.chars() {
+                re = re.derive(&ch).1;
+                round_trip(&re);
+            }
+        }
+    }
+
+    #[test]
+    fn display_is_unambiguous() {
+        let a = || cheese('a');
+        assert_eq!(
+            (a() * (cheese('b') | sundae("c")).star()).to_string(),
+            "a(b|\"c\")*"
+        );
+        assert_eq!((a() * cheese('b')).star().to_string(), "(ab)*");
+        assert_eq!(a().star().neg().to_string(), "!a*");
+        assert_eq!(a().neg().star().to_string(), "(!a)*");
+        assert_eq!(
+            ((a() | sundae("b")) & sundae("c")).to_string(),
+            "(a|\"b\")&\"c\""
+        );
+        assert_eq!(toppings("ab]").to_string(), "[\\]ab]");
+        assert_eq!(cheese('0'..='9').fan(3).to_string(), "[0-9]{3}");
+        assert_eq!(sundae("a\"b\n").to_string(), "\"a\\\"b\\n\"");
+        assert_eq!(cheese('*').to_string(), "\\*");
+    }
+
+    #[test]
+    fn display_round_trips() {
+        round_trip(&EPS);
+        round_trip(&NUL);
+        round_trip(&NUL.neg());
+        round_trip(&sundae(""));
+        round_trip(&Re::Chars(Charset::all()));
+        round_trip(&Re::Chars(Charset::new()));
+        round_trip(&toppings("\0\t,;%ε "));
+        round_trip(&cheese('\u{1f927}'));
+        round_trip(&cheese('a'..='\u{d7ff}'));
+        round_trip(&cheese('a').moon_phase(7, 3));
+        round_trip(&(cheese('a') * cheese('b')).fan(4).star());
+        round_trip(&(sundae("x") * cheese('y').star()).neg().fickle());
+    }
+
+    #[test]
+    fn derivatives_round_trip() {
+        round_trip_derivatives(
+            (toppings(",").fickle()
+                * toppings(" ").star()
+                * (sundae("sore throat") | sundae("runny nose") | sundae("cough")))
+            .sun()
+                * cheese('\u{1f927}'),
+            &["sore throat, cough\u{1f927}", "runny", ", cou"],
+        );
+        round_trip_derivatives(
+            NUL.neg()
+                & (NUL.neg()
+                    * consider(
+                        vec![toppings("cdb"), sundae("cdb") * toppings("db").star().neg()],
+                        2,
+                        3,
+                    ))
+                & (cheese('0'..='9').moon_phase(1, 3) * cheese('a'..='f').moon_phase(2, 3))
+                    .moon_phase(0, 2)
+                & (sundae("10") * EPS.neg()).moon_phase(0, 3)
+                & (NUL.neg() * (cheese('a'..='f') * NUL.neg()).fan(6)).neg(),
+            &["10174cdbf10810c", "cdbcdb"],
+        );
+        round_trip(&(cheese('a').capture("x_1") * cheese('b')).capture("y"));
+        round_trip_derivatives(
+            consider_lsd(vec![toppings("05a"), toppings("16b"), sundae("27")], 2, 7),
+            &["1a27b0"],
+        );
+        round_trip_derivatives(
+            (cheese('a') * toppings("bc").fickle()).repeat(20, Some(40))
+                | (sundae("ab") | cheese('b')).at_least(30),
+            &["abacab", "bbab"],
+        );
+        round_trip_derivatives(
+            crate::luhn(('0'..='9').map(cheese))
+                & crate::weighted_sum(vec![toppings("01234"), toppings("56789")], &[3, 1], 2, 7),
+            &["7992739871", "00"],
+        );
+        round_trip_derivatives(
+            consider_any(vec![toppings("ab"), sundae("c")], vec![1, 4, 6], 1 << 100)
+                & consider_lsd_any(vec![toppings("ab"), sundae("c")], vec![0, 3], 12),
+            &["acbcab", "cc"],
+        );
+        round_trip_derivatives(
+            crate::consider_parses(
+                vec![toppings("cdb"), sundae("cdb")],
+                vec![1],
+                3,
+                crate::Parses::All,
+            ) | crate::consider_lsd_parses(
+                vec![toppings("ab"), sundae("ab")],
+                vec![0],
+                5,
+                crate::Parses::Unique,
+            ),
+            &["cdbcdb", "abba"],
+        );
+    }
+
+    #[test]
+    fn bounded_repetition() {
+        let ab = || cheese('a') * cheese('b').fickle();
+        for &(min, max) in &[
+            (0, Some(2)),
+            (2, Some(4)),
+            (3, None),
+            (1, None),
+            (0, Some(1)),
+        ] {
+            let mut counted = compile(ab().repeat(min, max));
+            let mut unrolled = compile(
+                (min..=max.unwrap_or(8))
+                    .map(|n| ab().fan(n))
+                    .reduce(|a, b| a | b)
+                    .unwrap(),
+            );
+            for s in &strings("ab", 7) {
+                assert_eq!(counted.matches(s), unrolled.matches(s), "{:?} {:?}", max, s);
+            }
+        }
+
+        let mut m = compile(cheese('a').fickle().repeat(2, Some(3)));
+        assert!(m.matches(""));
+        assert!(m.matches("aaa"));
+        assert!(!m.matches("aaaa"));
+
+        // `{n}` and `{n,n}` agree when the piece may be empty.
+        let b = || cheese('b').fickle();
+        let (mut exact, mut bounded) = (compile(b().fan(3)), compile(b().repeat(3, Some(3))));
+        for s in &["", "b", "bb", "bbb", "bbbb"] {
+            assert_eq!(exact.matches(s), bounded.matches(s), "{:?}", s);
+        }
+        assert!(exact.matches("") && exact.matches("b") && !exact.matches("bbbb"));
+        let mut parsed = compile(Re::parse("(b?){3}").unwrap());
+        assert!(parsed.matches("") && parsed.matches("b"));
+        let long = || (cheese('a') | EPS) * cheese('b').fickle();
+        assert!(
+            Re::equivalent(&long().fan(40), &long().repeat(40, Some(40)))
+                .unwrap()
+                .holds()
+        );
+
+        assert_eq!(cheese('a').repeat(3, Some(8)).to_string(), "a{3,8}");
+        assert_eq!(
+            (cheese('a') * cheese('b')).at_least(2).to_string(),
+            "(ab){2,}"
+        );
+        assert_eq!(cheese('a').repeat(0, None), cheese('a').star());
+        assert_eq!(cheese('a').repeat(3, Some(2)), NUL);
+        assert_eq!(cheese('a').repeat(0, Some(0)), EPS);
+        assert_eq!(cheese('a').repeat(3, Some(0)), NUL);
+        // Built by hand, `{0,0}` still only matches the empty string.
+        let mut m = compile(Repeat(ReRef::from(cheese('a')), 0, Some(0)));
+        assert!(m.matches(""));
+        assert!(!m.matches("a"));
+        round_trip_derivatives(ab().repeat(2, Some(4)) * ab().at_least(1), &["abaabab"]);
+    }
+
+    #[test]
+    fn modular_repetition() {
+        let ab = || cheese('a') * cheese('b').fickle();
+        let mut m = compile(ab().repeat_mod(1, 3, 5));
+        for n in 0..12 {
+            assert_eq!(m.matches(&"ab".repeat(n)), n >= 5 && n % 3 == 1, "{}", n);
+        }
+        assert_eq!(ab().repeat_mod(4, 3, 2), ab().repeat_mod(1, 3, 4));
+        assert_eq!(
+            cheese('a').fickle().repeat_mod(1, 2, 4),
+            cheese('a').fickle().star()
+        );
+        assert_eq!(
+            (cheese('a') * cheese('b')).repeat_mod(2, 3, 0).to_string(),
+            "%repeat_mod(ab; 2, 3, 2)"
+        );
+        round_trip_derivatives(ab().repeat_mod(0, 4, 6), &["abaabab"]);
+
+        // The moons of the insurance pattern in `src/bin/dr.rs`, each with
+        // the number of pieces until its phase reaches its planet.
+        let digits = || cheese('0'..='9').moon_phase(1, 3);
+        let letters = || cheese('a'..='f').moon_phase(2, 3);
+        let ten = || sundae("10") * EPS.neg();
+        let moons = (digits() * letters()).moon_phase(0, 2) & ten().moon_phase(0, 3);
+        let counted = (cheese('0'..='9').repeat_mod(2, 3, 2)
+            * cheese('a'..='f').repeat_mod(1, 3, 1))
+        .repeat_mod(0, 2, 2)
+            & ten().repeat_mod(0, 3, 3);
+        let (mut moons, mut counted) = (compile(moons), compile(counted));
+        for s in &strings("10a", 8) {
+            assert_eq!(moons.matches(s), counted.matches(s), "{:?}", s);
+        }
+        assert!(counted.matches("10174cdbf10810c"));
+        // A phase past the planet takes a few pieces to come back around.
+        let mut moon = compile(cheese('a').moon_phase(10, 3));
+        let mut count = compile(cheese('a').repeat_mod(2, 3, 5));
+        for n in 0..20 {
+            let s = "a".repeat(n);
+            assert_eq!(moon.matches(&s), count.matches(&s), "{}", n);
+        }
+
+        // Counts near the top of `usize` are found without overflowing.
+        let max = usize::MAX;
+        assert_eq!(Re::first_count(1, max - 1, max - 5), Some(max));
+        assert_eq!(Re::first_count(3, max - 1, max - 5), None);
+        assert_eq!(Re::first_count(max - 1, max, 2), Some(max - 1));
+        assert_eq!(Re::first_count(5, max, 3), Some(5));
+        assert_eq!(Re::first_count(0, 2, max), None);
+    }
+
+    #[test]
+    fn length_constraints() {
+        let digits = || cheese('0'..='9').star();
+        let mut m = compile(digits() & Re::length_between(3, Some(5)) & Re::length_mod(0, 2));
+        assert!(m.matches("1234"));
+        assert!(!m.matches("123"));
+        assert!(!m.matches("123456"));
+        assert!(!m.matches("12a4"));
+        // Only the digit class splits the chars; the lengths never do.
+        let (range, _) = ReRef::from(Re::length_mod(1, 3)).derive(&'x');
+        assert_eq!(range, CharRange::all());
+        let (range, _) = ReRef::from(digits() & Re::length_between(3, None)).derive(&'5');
+        assert_eq!(range, CharRange::from('0'..='9'));
+
+        assert_eq!(
+            Re::length_between(2, Some(8)) & Re::length_between(5, None) & digits(),
+            Re::length_between(5, Some(8)) & digits()
+        );
+        assert_eq!(
+            Re::length_between(4, Some(8)) & Re::length_between(0, Some(3)),
+            NUL
+        );
+        assert_eq!(Re::length_mod(1, 3) & Re::length_mod(2, 3), NUL);
+        assert_eq!(Re::length_between(0, None), NUL.neg());
+        assert_eq!(Re::length_mod(5, 1), NUL.neg());
+        // A modulus near the top of `usize` counts down without wrapping.
+        let mut huge = compile(Re::length_mod(2, usize::MAX));
+        assert!(huge.matches("ab"));
+        assert!(!huge.matches("abc"));
+        assert!(!huge.matches(""));
+
+        round_trip(&Re::length_between(3, Some(3)));
+        round_trip(&Re::length_between(3, None));
+        round_trip_derivatives(
+            (Re::length_between(2, Some(6)) | Re::length_mod(1, 4)) & toppings("ab").star(),
+            &["abbab"],
+        );
+    }
+
+    #[test]
+    fn shuffle_interleaves() {
+        let symptoms =
+            || (sundae("cough") | sundae("fever")) * (toppings(",") * sundae("cough")).star();
+        let severity = || toppings("+!").star();
+        let mut m = compile(symptoms().shuffle(severity()));
+        assert!(m.matches("cough"));
+        assert!(m.matches("c+ou!gh,co!!ugh+"));
+        assert!(m.matches("+fe!ver"));
+        assert!(!m.matches("c+ou!gh,fever"));
+        assert!(!m.matches("cou?gh"));
+
+        let mut m = compile(sundae("ab").shuffle(sundae("ab")));
+        for s in ["aabb", "abab"] {
+            assert!(m.matches(s));
+        }
+        for s in ["ab", "abba", "baab"] {
+            assert!(!m.matches(s));
+        }
+
+        let (a, b, c) = (|| cheese('a'), || cheese('b'), || cheese('c'));
+        assert_eq!(a().shuffle(b()), b().shuffle(a()));
+        assert_eq!(a().shuffle(b()).shuffle(c()), a().shuffle(b().shuffle(c())));
+        assert_eq!(a().shuffle(EPS), a());
+        assert_eq!(a().shuffle(NUL), NUL);
+        assert_ne!(a().shuffle(a()), a());
+
+        round_trip(&a().shuffle(b() * c()).shuffle(a().star()));
+        round_trip_derivatives(
+            symptoms().shuffle(severity()) | sundae("xy").shuffle(sundae("yz")),
+            &["c+ough,", "xyyz"],
+        );
+    }
+
+    #[test]
+    fn lookaround_is_intersection() {
+        let any = || NUL.neg();
+        let digit = || cheese('0'..='9');
+        let x = || toppings("ab").star();
+        let y = || (digit() | cheese('a')).star();
+
+        assert_eq!(
+            x() * lookahead(digit()) * y(),
+            x() * ((digit() * any()) & y())
+        );
+        assert_eq!(
+            x() * negative_lookahead(digit()) * y(),
+            x() * ((digit() * any()).neg() & y())
+        );
+        assert_eq!(
+            sundae("cd") * x() * lookbehind(sundae("cdb")) * y(),
+            ((sundae("cd") * x()) & (any() * sundae("cdb"))) * y()
+        );
+        assert_eq!(
+            x() * negative_lookbehind(sundae("cdb")) * y(),
+            (x() & (any() * sundae("cdb")).neg()) * y()
+        );
+
+        // "a" not preceded by "cdb" and followed by a digit.
+        let mut m = compile(
+            any() * negative_lookbehind(sundae("cdb")) * cheese('a') * lookahead(digit()) * any(),
+        );
+        assert!(m.matches("xa1"));
+        assert!(m.matches("cdba1a2"));
+        assert!(!m.matches("cdba1"));
+        assert!(!m.matches("xab"));
+
+        // With nothing on their side, assertions look at the empty string.
+        assert!(!compile(lookahead(digit())).matches(""));
+        assert!(compile(x() * negative_lookahead(digit())).matches("ab"));
+        assert!(!compile(x() * lookahead(digit())).matches("ab"));
+        assert!(compile(lookbehind(EPS) * x()).matches("ab"));
+    }
+
+    #[test]
+    fn standalone_lookaround_round_trips() {
+        round_trip(&(cheese('a') * lookahead(cheese('b'))));
+        round_trip(&(negative_lookbehind(sundae("cdb")) * cheese('a')));
+        round_trip(&(lookbehind(cheese('b')) | negative_lookahead(cheese('c').star())));
+    }
+
+    #[test]
+    fn literals_from_runtime_strings() {
+        let words: Vec<String> = vec!["über".into(), "alles".into()];
+        let re = Re::alt(words.iter().map(|w| ReRef::from(sundae(w.as_str()))));
+        let mut m = compile(re);
+        assert!(m.matches("über"));
+        assert!(m.matches("alles"));
+        assert!(!m.matches("üb"));
+
+        let lit = LitStr::from(String::from("ü😀x"));
+        let (_, rest) = Lit(lit.clone()).derive(&'ü');
+        match rest.as_ref() {
+            Lit(rest) => {
+                assert_eq!(rest.as_str(), "😀x");
+                assert!(Arc::ptr_eq(&rest.buf, &lit.buf));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(sundae("😀x"), Lit(lit.tail('ü'.len_utf8())));
+    }
+}
@@ -0,0 +1,155 @@
+//! Arithmetic on residues modulo a `u128`, without overflow.
+//!
+//! `Consider` and `ConsiderLsd` keep their running value below the modulus,
+//! but a product of two such values can be far beyond `u128`. These helpers
+//! only ever hold numbers below the modulus, so any modulus up to `u128::MAX`
+//! works.
+
+use std::sync::Arc;
+
+/// A set of residues below some modulus: the listed ones, sorted and without
+/// duplicates, or every residue but those.
+#[derive(Clone, Eq, PartialEq, PartialOrd, Ord, Hash, Debug)]
+pub struct Residues {
+    listed: Arc<[u128]>,
+    others: bool,
+}
+
+impl Residues {
+    /// The residues in `targets`, dropping those that are not below `within`
+    /// since a residue never reaches them.
+    pub(crate) fn new<T: IntoIterator<Item = u128>>(targets: T, within: u128) -> Self {
+        let mut listed: Vec<_> = targets.into_iter().filter(|&t| t < within).collect();
+        listed.sort_unstable();
+        listed.dedup();
+        Residues {
+            listed: listed.into(),
+            others: false,
+        }
+    }
+
+    /// Every residue below `within` that is not in `self`.
+    pub(crate) fn complement(&self) -> Self {
+        Residues {
+            listed: self.listed.clone(),
+            others: !self.others,
+        }
+    }
+
+    pub fn contains(&self, residue: u128) -> bool {
+        self.listed.binary_search(&residue).is_ok() != self.others
+    }
+
+    pub(crate) fn is_empty(&self, within: u128) -> bool {
+        if self.others {
+            self.listed.len() as u128 == within
+        } else {
+            self.listed.is_empty()
+        }
+    }
+
+    /// The residues that are listed, or that are left out if
+    /// [`is_complement`](Self::is_complement).
+    pub fn listed(&self) -> &[u128] {
+        &self.listed
+    }
+
+    pub fn is_complement(&self) -> bool {
+        self.others
+    }
+
+    /// The residues below `within` that a one-to-one map `g` takes into
+    /// `self`, where `f` is the inverse of `g`: for each listed `t`, the
+    /// residue that `g` takes to `t`, or `None` if there is none.
+    pub(crate) fn preimage<F: FnMut(u128) -> Option<u128>>(&self, f: F, within: u128) -> Self {
+        Residues {
+            others: self.others,
+            ..Residues::new(self.listed.iter().cloned().filter_map(f), within)
+        }
+    }
+}
+
+/// `a + b` modulo `m`, for `a` and `b` below `m`.
+pub(crate) fn add_mod(a: u128, b: u128, m: u128) -> u128 {
+    if a >= m - b {
+        a - (m - b)
+    } else {
+        a + b
+    }
+}
+
+/// `a - b` modulo `m`, for `a` and `b` below `m`.
+pub(crate) fn sub_mod(a: u128, b: u128, m: u128) -> u128 {
+    if a >= b {
+        a - b
+    } else {
+        m - (b - a)
+    }
+}
+
+/// `a * b` modulo `m`.
+pub(crate) fn mul_mod(a: u128, b: u128, m: u128) -> u128 {
+    let (a, b) = (a % m, b % m);
+    if let Some(product) = a.checked_mul(b) {
+        return product % m;
+    }
+    // Double and add, most significant bit of `b` first.
+    let mut product = 0;
+    for bit in (0..128 - b.leading_zeros()).rev() {
+        product = add_mod(product, product, m);
+        if b >> bit & 1 == 1 {
+            product = add_mod(product, a, m);
+        }
+    }
+    product
+}
+
+pub(crate) fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// The inverse of `a` modulo `m`, if `a` and `m` are coprime.
+pub(crate) fn inverse(a: u128, m: u128) -> Option<u128> {
+    // Extended Euclid, keeping the coefficient of `a` reduced modulo `m`.
+    let (mut old_r, mut r) = (a % m, m);
+    let (mut old_s, mut s) = (1 % m, 0);
+    while r != 0 {
+        let q = old_r / r;
+        (old_r, r) = (r, old_r - q * r);
+        (old_s, s) = (s, sub_mod(old_s, mul_mod(q % m, s, m), m));
+    }
+    if old_r == 1 {
+        Some(old_s)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn arithmetic_near_the_top() {
+        let m = u128::MAX - 158; // The largest prime below 2^128.
+        assert_eq!(add_mod(m - 1, m - 1, m), m - 2);
+        assert_eq!(sub_mod(1, 2, m), m - 1);
+        assert_eq!(mul_mod(m - 1, m - 1, m), 1);
+        assert_eq!(mul_mod(1 << 100, 1 << 100, 1_000_000_007), {
+            let r = (1u128 << 100) % 1_000_000_007;
+            r * r % 1_000_000_007
+        });
+        let inv = inverse(12345, m).unwrap();
+        assert_eq!(mul_mod(inv, 12345, m), 1);
+        assert_eq!(inverse(6, 9), None);
+        assert_eq!(inverse(2, 9), Some(5));
+        assert_eq!(gcd(0, 12), 12);
+        let targets = Residues::new(vec![4, 1, 9, 4], 7);
+        assert_eq!(targets.listed(), &[1, 4]);
+        assert!(targets.complement().contains(2) && !targets.complement().contains(4));
+        assert!(Residues::new(0..3, 3).complement().is_empty(3));
+    }
+}
@@ -0,0 +1,236 @@
+//! Reversing the language of an [`Re`].
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::count;
+use crate::intern::ReRef;
+use crate::parses;
+use crate::re::{sundae, Re, Res};
+use crate::residue;
+
+impl Re {
+    /// Matches exactly the reverses of the strings this one matches.
+    ///
+    /// `Fan`, `Moon` and `RepeatMod` only count pieces, so they reverse piece
+    /// by piece.
+    /// `Consider` reads its most significant digit first and turns into
+    /// `ConsiderLsd`, which reads it last. Going back the other way, the
+    /// running sum and place of a `ConsiderLsd` are solved for the values that
+    /// `Consider` would have to reach, which may be modulo a divisor of the
+    /// original modulus. The residuals of a `Count` end up after the counted
+    /// pieces, and an `Ambiguous` swaps its head and tail.
+    ///
+    /// # Panics
+    ///
+    /// If a custom node does not provide its reverse.
+    pub fn reverse(&self) -> Re {
+        let mut done = HashMap::new();
+        reverse(&mut done, &ReRef::from(self.clone()))
+            .as_ref()
+            .clone()
+    }
+
+    /// Like [`reverse`](Self::reverse), but `None` instead of a panic when a
+    /// custom node does not provide its reverse.
+    pub fn try_reverse(&self) -> Option<Re> {
+        let re = ReRef::from(self.clone());
+        reversible(&mut HashSet::new(), &re).then(|| self.reverse())
+    }
+}
+
+fn reversible(seen: &mut HashSet<ReRef>, re: &ReRef) -> bool {
+    if !seen.insert(re.clone()) {
+        return true;
+    }
+    match &**re {
+        Re::Custom(node) => node.reverse().is_some(),
+        _ => re.children().all(|x| reversible(seen, x)),
+    }
+}
+
+fn reverse(done: &mut HashMap<ReRef, ReRef>, re: &ReRef) -> ReRef {
+    if let Some(reversed) = done.get(re) {
+        return reversed.clone();
+    }
+    let reversed = match &**re {
+        Re::Lit(s) => ReRef::from(sundae(s.chars().rev().collect::<String>())),
+        Re::Seq(a, b) => {
+            let (a, b) = (reverse(done, a), reverse(done, b));
+            Re::seq(b, a)
+        }
+        Re::Consider(choices, value, targets, within) => ReRef::from(Re::ConsiderLsd(
+            reverse_all(done, choices),
+            *value,
+            0,
+            1 % within,
+            targets.clone(),
+            *within,
+        )),
+        Re::ConsiderLsd(choices, value, sum, place, targets, within) => {
+            // The rest reads as a number `total` with `sum + place * total`
+            // in `targets`. That pins `total` down modulo `within / g`, where
+            // `g` is the gcd of `place` and `within`.
+            let g = residue::gcd(*place, *within);
+            let modulus = within / g;
+            let inverse = residue::inverse(place / g % modulus, modulus)
+                .expect("coprime after dividing out the gcd");
+            let totals = targets.preimage(
+                |target| {
+                    let rest = residue::sub_mod(target, *sum, *within);
+                    rest.is_multiple_of(g)
+                        .then(|| residue::mul_mod(rest / g % modulus, inverse, modulus))
+                },
+                modulus,
+            );
+            if totals.is_empty(modulus) {
+                ReRef::from(Re::Nul)
+            } else {
+                let choices = reverse_all(done, choices);
+                ReRef::from(Re::Consider(choices, value % modulus, totals, modulus))
+            }
+        }
+        Re::Look(look, x) => ReRef::from(Re::Look(look.reverse(), reverse(done, x))),
+        Re::Checksum(rule, pos, sums, targets) => {
+            let choices = reverse_all(done, &rule.choices);
+            ReRef::from(rule.reverse(choices.as_ref().clone(), *pos, sums, targets))
+        }
+        Re::Custom(node) => node
+            .reverse()
+            .unwrap_or_else(|| panic!("{} has no reverse", node)),
+        Re::Count(a, counters) => {
+            let a = reverse(done, a);
+            let parts: Vec<_> = counters
+                .iter()
+                .map(|(x, counts)| {
+                    let x = reverse(done, x);
+                    Re::seq(count::counted(a.clone(), counts.clone()), x)
+                })
+                .collect();
+            Re::alt(parts)
+        }
+        Re::Ambiguous(choices, head, tail) => {
+            let choices = reverse_all(done, choices);
+            let (head, tail) = (reverse(done, head), reverse(done, tail));
+            parses::ambiguous(choices, tail, head)
+        }
+        _ => re.map_children(|x| reverse(done, x)),
+    };
+    done.insert(re.clone(), reversed.clone());
+    reversed
+}
+
+fn reverse_all(done: &mut HashMap<ReRef, ReRef>, choices: &Res) -> Arc<Res> {
+    Arc::new(Res::from(
+        choices.iter().map(|x| reverse(done, x)).collect::<Vec<_>>(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::strings;
+    use crate::{
+        cheese, compile, consider, consider_any, consider_lsd_any, consider_parses, lookbehind,
+        negative_lookahead, sundae, toppings, Parses, EPS, NUL,
+    };
+
+    fn check_reverse(re: Re, alphabet: &str, len: usize) {
+        let mut forward = compile(re.clone());
+        let mut backward = compile(re.reverse());
+        let mut twice = compile(re.reverse().reverse());
+        for s in strings(alphabet, len) {
+            let expected = forward.matches(&s);
+            let rev: String = s.chars().rev().collect();
+            assert_eq!(backward.matches(&rev), expected, "{} on {:?}", re, s);
+            assert_eq!(twice.matches(&s), expected, "{} on {:?}", re, s);
+        }
+    }
+
+    #[test]
+    fn reverses_every_variant() {
+        let a = || cheese('a');
+        let b = || cheese('b');
+        check_reverse(sundae("abc") | (sundae("ba").star() * a()), "abc", 6);
+        check_reverse((a() * b().fickle()).fan(3), "ab", 8);
+        check_reverse((a() * b().star()).moon_phase(0, 3), "ab", 8);
+        check_reverse((sundae("ab") * NUL.neg()).neg() & b().star().neg(), "ab", 7);
+        check_reverse(
+            consider(vec![a(), sundae("bb"), a() * b()], 2, 5) & EPS.neg(),
+            "ab",
+            8,
+        );
+        check_reverse(
+            consider(vec![toppings("ab"), sundae("ba")], 1, 3).moon(2),
+            "ab",
+            7,
+        );
+        check_reverse(sundae("ab").shuffle(b() * a().star()), "abc", 6);
+        check_reverse(
+            (lookbehind(b()) | negative_lookahead(a())) * (a() * negative_lookahead(b())).star(),
+            "ab",
+            6,
+        );
+    }
+
+    #[test]
+    fn reverses_derivatives() {
+        let re = ReRef::from(consider(vec![cheese('a'), sundae("ab"), cheese('b')], 4, 7));
+        let (_, derived) = re.derive(&'b');
+        let (_, derived) = derived.derive(&'a');
+        check_reverse(derived.as_ref().clone(), "ab", 7);
+        let lsd = ReRef::from(re.reverse());
+        let (_, derived) = lsd.derive(&'a');
+        check_reverse(derived.as_ref().clone(), "ab", 7);
+
+        let re = ReRef::from((cheese('a') * cheese('b').fickle()).repeat(0, Some(20)));
+        let (_, derived) = re.derive(&'a');
+        assert!(derived.has_counters());
+        check_reverse(derived.as_ref().clone(), "ab", 7);
+        check_reverse(Re::parse("%count(ab?,b?,ε; 1-3,5; 0-2)").unwrap(), "ab", 8);
+    }
+
+    #[test]
+    fn reverses_checksums() {
+        let digits = || vec![cheese('a'), sundae("ba"), cheese('b')];
+        for re in [
+            crate::luhn(digits()),
+            crate::weighted_sum(digits(), &[1, 2, 5], 3, 7),
+            crate::digit_sum(digits(), 1, 3),
+        ] {
+            check_reverse(re.clone(), "ab", 7);
+            let mut derived = ReRef::from(re);
+            for ch in "abba".chars() {
+                derived = derived.derive(&ch).1;
+                check_reverse(derived.as_ref().clone(), "ab", 6);
+            }
+        }
+    }
+
+    #[test]
+    fn reverses_parse_modes() {
+        let digits = || vec![cheese('a'), sundae("ab"), sundae("ba"), cheese('b')];
+        for parses in [Parses::All, Parses::Unique] {
+            let re = consider_parses(digits(), vec![1, 2], 5, parses);
+            check_reverse(re.clone(), "ab", 7);
+            let mut derived = ReRef::from(re);
+            for ch in "aba".chars() {
+                derived = derived.derive(&ch).1;
+                check_reverse(derived.as_ref().clone(), "ab", 5);
+            }
+        }
+    }
+
+    #[test]
+    fn reverses_target_sets() {
+        let digits = || vec![cheese('a'), sundae("ba"), cheese('b')];
+        check_reverse(consider_any(digits(), vec![1, 4, 5], 6), "ab", 7);
+        // A place that shares a factor with the modulus leaves `Consider`
+        // working modulo a divisor of it.
+        let mut lsd = ReRef::from(consider_lsd_any(digits(), vec![0, 3, 8], 12));
+        for ch in "abab".chars() {
+            lsd = lsd.derive(&ch).1;
+            check_reverse(lsd.as_ref().clone(), "ab", 6);
+        }
+    }
+}
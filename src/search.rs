@@ -0,0 +1,236 @@
+//! Finding matches inside a longer text.
+//!
+//! A [`Searcher`] reports leftmost-longest spans as byte offsets. It keeps three
+//! lazily built DFAs: one for `!∅ rev(r)`, run backwards over the whole text to
+//! mark every offset where a match starts, one for `r` itself, run forwards
+//! from the leftmost start to find the longest match there, and one for
+//! `!∅ r` to find the earliest end for [`Searcher::shortest_match`].
+//! Assertions only see the text of the match, as when matching `r` alone, so
+//! the lookbehinds at the start of `r` and of `rev(r)` are decided before
+//! `!∅` is put in front.
+//!
+//! If `r` has a custom node without a reverse (see [`crate::custom`]), the
+//! starts are found by running `r` forwards from every offset instead, which
+//! takes time quadratic in the length of the text.
+
+use crate::intern::ReRef;
+use crate::re::{Re, EPS, NUL};
+use crate::{Cursor, Matcher};
+
+/// `re` with the lookbehinds at its start decided on the empty string, which
+/// is all they see of the text (see [`Lookaround`](crate::Lookaround)). Left
+/// in, `Re::seq` would let them look into the `!∅` put in front of `re` to
+/// search for it.
+fn settle_lookbehinds(re: &ReRef) -> ReRef {
+    match &**re {
+        Re::Look(look, _) if !look.is_ahead() => ReRef::from(if re.nullable() { EPS } else { NUL }),
+        Re::Seq(first, rest) if matches!(&**first, Re::Look(look, _) if !look.is_ahead()) => {
+            Re::seq(settle_lookbehinds(first), settle_lookbehinds(rest))
+        }
+        _ => re.clone(),
+    }
+}
+
+#[derive(Debug)]
+pub struct Searcher {
+    forward: Matcher,
+    backward: Option<Matcher>,
+    unanchored: Matcher,
+}
+
+impl Searcher {
+    pub fn new(re: ReRef) -> Self {
+        let re = settle_lookbehinds(&re);
+        let any = || ReRef::from(NUL.neg());
+        Searcher {
+            backward: re
+                .try_reverse()
+                .map(|rev| Matcher::new(Re::seq(any(), settle_lookbehinds(&rev.into())))),
+            unanchored: Matcher::new(Re::seq(any(), re.clone())),
+            forward: Matcher::new(re),
+        }
+    }
+
+    /// Marks the byte offsets in `text` where some match starts.
+    fn starts(&mut self, text: &str) -> Vec<bool> {
+        let mut starts = vec![false; text.len() + 1];
+        let backward = match &mut self.backward {
+            Some(backward) => backward,
+            None => {
+                for i in (0..=text.len()).filter(|&i| text.is_char_boundary(i)) {
+                    starts[i] = self.longest_from(text, i).is_some();
+                }
+                return starts;
+            }
+        };
+        let mut cursor = Cursor::START;
+        starts[text.len()] = backward.nullable(&cursor);
+        for (i, c) in text.char_indices().rev() {
+            cursor = backward.step(&cursor, &c);
+            starts[i] = backward.nullable(&cursor);
+        }
+        starts
+    }
+
+    /// The end of the longest match starting at `start`.
+    fn longest_from(&mut self, text: &str, start: usize) -> Option<usize> {
+        let mut cursor = Cursor::START;
+        let mut end = None;
+        if self.forward.nullable(&cursor) {
+            end = Some(start);
+        }
+        for (i, c) in text[start..].char_indices() {
+            cursor = self.forward.step(&cursor, &c);
+            if self.forward.nullable(&cursor) {
+                end = Some(start + i + c.len_utf8());
+            } else if self.forward.is_dead(&cursor) {
+                break;
+            }
+        }
+        end
+    }
+
+    /// The leftmost match in `text`, extended as far as it will go.
+    pub fn find(&mut self, text: &str) -> Option<(usize, usize)> {
+        self.find_iter(text).next()
+    }
+
+    /// Every leftmost-longest match in `text`, each one starting where the
+    /// previous one ended. An empty match moves the search on by one char.
+    pub fn find_iter<'s, 't>(&'s mut self, text: &'t str) -> FindIter<'s, 't> {
+        FindIter {
+            starts: self.starts(text),
+            searcher: self,
+            text,
+            pos: 0,
+        }
+    }
+
+    /// The offset where the earliest ending match ends. This only needs a
+    /// single forward pass that stops as soon as a match is seen.
+    pub fn shortest_match(&mut self, text: &str) -> Option<usize> {
+        let mut cursor = Cursor::START;
+        if self.unanchored.nullable(&cursor) {
+            return Some(0);
+        }
+        for (i, c) in text.char_indices() {
+            cursor = self.unanchored.step(&cursor, &c);
+            if self.unanchored.nullable(&cursor) {
+                return Some(i + c.len_utf8());
+            }
+        }
+        None
+    }
+}
+
+pub struct FindIter<'s, 't> {
+    searcher: &'s mut Searcher,
+    text: &'t str,
+    starts: Vec<bool>,
+    pos: usize,
+}
+
+impl<'s, 't> Iterator for FindIter<'s, 't> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = (self.pos..self.starts.len()).find(|&i| self.starts[i])?;
+        let end = self
+            .searcher
+            .longest_from(self.text, start)
+            .expect("a match starts here");
+        self.pos = if end > start {
+            end
+        } else {
+            end + self.text[end..].chars().next().map_or(1, char::len_utf8)
+        };
+        Some((start, end))
+    }
+}
+
+pub fn compile_search<T: Into<ReRef>>(re: T) -> Searcher {
+    Searcher::new(re.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::strings;
+    use crate::{
+        cheese, compile, lookahead, lookbehind, negative_lookahead, negative_lookbehind, sundae,
+        EPS,
+    };
+
+    #[test]
+    fn leftmost_longest() {
+        let mut s = compile_search(sundae("ab") | sundae("abcd") | sundae("bcdef"));
+        assert_eq!(s.find("xabcdefg"), Some((1, 5)));
+        assert_eq!(s.find("xbcdefabcd"), Some((1, 6)));
+        assert_eq!(s.find("xyz"), None);
+        assert_eq!(
+            s.find_iter("ab abcd abcdef").collect::<Vec<_>>(),
+            vec![(0, 2), (3, 7), (8, 12)]
+        );
+        assert_eq!(s.shortest_match("xabcdefg"), Some(3));
+        assert_eq!(s.shortest_match("xbcde"), None);
+    }
+
+    #[test]
+    fn offsets_are_in_bytes() {
+        let mut s = compile_search(cheese('é').sun());
+        assert_eq!(
+            s.find_iter("aéébé").collect::<Vec<_>>(),
+            vec![(1, 5), (6, 8)]
+        );
+    }
+
+    #[test]
+    fn empty_matches_advance() {
+        let mut s = compile_search(cheese('a').star());
+        assert_eq!(
+            s.find_iter("baaé").collect::<Vec<_>>(),
+            vec![(0, 0), (1, 3), (3, 3), (5, 5)]
+        );
+        assert_eq!(s.shortest_match("b"), Some(0));
+        assert_eq!(compile_search(EPS).find(""), Some((0, 0)));
+    }
+
+    #[test]
+    fn agrees_with_brute_force() {
+        let res = [
+            (sundae("ab") * cheese('b').star()) | (cheese('a') * NUL.neg() * cheese('a')),
+            lookbehind(cheese('a')) * cheese('b'),
+            negative_lookbehind(cheese('b')) * lookbehind(EPS) * cheese('a').sun(),
+            cheese('a').sun() * lookahead(cheese('b')),
+            cheese('a') * lookbehind(cheese('a')) * NUL.neg() * negative_lookahead(cheese('a')),
+        ];
+        for re in &res {
+            let mut s = compile_search(re.clone());
+            let mut m = compile(re.clone());
+            for t in &strings("ab", 6) {
+                let expected = (0..=t.len()).find_map(|i| {
+                    (i..=t.len())
+                        .rev()
+                        .find(|&j| m.matches(&t[i..j]))
+                        .map(|j| (i, j))
+                });
+                assert_eq!(s.find(t), expected, "{} in {}", re, t);
+                let shortest = (0..=t.len()).find(|&j| (0..=j).any(|i| m.matches(&t[i..j])));
+                assert_eq!(s.shortest_match(t), shortest, "{} in {}", re, t);
+            }
+        }
+    }
+
+    #[test]
+    fn assertions_do_not_see_around_the_match() {
+        let mut s = compile_search(lookbehind(cheese('a')) * cheese('b'));
+        assert_eq!(s.find("ab"), None);
+        assert_eq!(s.shortest_match("ab"), None);
+        let mut s = compile_search(negative_lookbehind(cheese('a')) * cheese('b'));
+        assert_eq!(s.find("ab"), Some((1, 2)));
+        assert_eq!(s.shortest_match("ab"), Some(2));
+        let mut s = compile_search(cheese('a') * lookahead(cheese('b')));
+        assert_eq!(s.find("ab"), None);
+        assert_eq!(s.shortest_match("ab"), None);
+    }
+}
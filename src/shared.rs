@@ -0,0 +1,220 @@
+//! A [`Matcher`](crate::Matcher) that can be queried from many threads at
+//! once.
+//!
+//! Each state owns its own transition table behind a read-write lock, so
+//! following a transition that some thread has already computed only takes a
+//! read lock on the current state. The table of all states is only locked when
+//! a derivative has to be computed for the first time. Runs through counted
+//! repetitions go the same way: the derivatives of their skeletons (see
+//! `count::Run`) sit behind one read-write lock, which is only taken for
+//! writing when a skeleton or one of its transitions is new.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::charmap::Charmap;
+use crate::count;
+use crate::intern::ReRef;
+use crate::Cursor;
+
+#[derive(Debug)]
+struct SharedState {
+    re: ReRef,
+    next: RwLock<Charmap<Cursor<Arc<SharedState>>>>,
+    nullable: bool,
+}
+
+impl From<ReRef> for SharedState {
+    fn from(re: ReRef) -> Self {
+        let nullable = re.nullable();
+        SharedState {
+            re,
+            next: RwLock::new(Charmap::new()),
+            nullable,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SharedMatcher {
+    initial: Arc<SharedState>,
+    states: Mutex<HashMap<ReRef, Arc<SharedState>>>,
+    counting: RwLock<count::Cache>,
+}
+
+impl SharedMatcher {
+    pub fn new(re: ReRef) -> Self {
+        let initial = Arc::new(SharedState::from(re.clone()));
+        SharedMatcher {
+            states: Mutex::new(vec![(re, initial.clone())].into_iter().collect()),
+            initial,
+            counting: RwLock::default(),
+        }
+    }
+
+    fn add_state(&self, re: ReRef) -> Arc<SharedState> {
+        let mut states = self.states.lock().expect("state table lock poisoned");
+        states
+            .entry(re)
+            .or_insert_with_key(|re| Arc::new(SharedState::from(re.clone())))
+            .clone()
+    }
+
+    fn cursor(&self, re: ReRef) -> Cursor<Arc<SharedState>> {
+        if !re.has_counters() {
+            Cursor::At(self.add_state(re))
+        } else {
+            Cursor::Counting(count::Run::new(re))
+        }
+    }
+
+    fn step(&self, cursor: &Cursor<Arc<SharedState>>, ch: &char) -> Cursor<Arc<SharedState>> {
+        assert!(*ch < std::char::MAX);
+        let state = match cursor {
+            Cursor::At(state) => state,
+            Cursor::Counting(run) => {
+                let cached = self
+                    .counting
+                    .read()
+                    .expect("counting cache lock poisoned")
+                    .get(run, ch);
+                let next = cached.unwrap_or_else(|| {
+                    self.counting
+                        .write()
+                        .expect("counting cache lock poisoned")
+                        .step(run, ch)
+                });
+                return match next {
+                    Ok(run) => Cursor::Counting(run),
+                    Err(re) => self.cursor(re),
+                };
+            }
+        };
+        if let Some(next) = state.next.read().expect("lock poisoned").get(ch) {
+            return next.clone();
+        }
+        let (range, next_re) = state.re.derive(ch);
+        let next = self.cursor(next_re);
+        let mut table = state.next.write().expect("lock poisoned");
+        // Another thread may have filled in the same range while we were
+        // deriving. It computed the same state, so either copy will do.
+        table.try_insert(range, next.clone());
+        next
+    }
+
+    fn run<I: Iterator<Item = char>>(&self, chars: I) -> bool {
+        let mut cursor = Cursor::At(self.initial.clone());
+        for c in chars {
+            cursor = self.step(&cursor, &c)
+        }
+        match cursor {
+            Cursor::At(state) => state.nullable,
+            Cursor::Counting(run) => run.nullable(),
+        }
+    }
+
+    pub fn matches(&self, s: &str) -> bool {
+        self.run(s.chars())
+    }
+
+    /// Matches raw bytes, each one read as the char with the same value.
+    pub fn matches_bytes(&self, bytes: &[u8]) -> bool {
+        self.run(bytes.iter().map(|&b| char::from(b)))
+    }
+
+    /// The number of states discovered so far.
+    pub fn state_count(&self) -> usize {
+        self.states.lock().expect("state table lock poisoned").len()
+    }
+}
+
+impl Drop for SharedMatcher {
+    fn drop(&mut self) {
+        // States point at each other, so break the cycles before the table
+        // lets go of them.
+        let states = self.states.get_mut().expect("state table lock poisoned");
+        for state in states.values() {
+            *state.next.write().expect("lock poisoned") = Charmap::new();
+        }
+    }
+}
+
+pub fn compile_shared<T: Into<ReRef>>(re: T) -> SharedMatcher {
+    SharedMatcher::new(re.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cheese, consider, sundae, toppings, EPS, NUL};
+
+    #[test]
+    fn shared_matcher_is_send_and_sync() {
+        fn check<T: Send + Sync>() {}
+        check::<SharedMatcher>();
+        check::<ReRef>();
+    }
+
+    #[test]
+    fn matches_from_many_threads() {
+        let matcher = compile_shared(
+            NUL.neg()
+                & (NUL.neg()
+                    * consider(
+                        vec![toppings("cdb"), sundae("cdb") * toppings("db").star().neg()],
+                        2,
+                        3,
+                    ))
+                & (NUL.neg() * cheese('1'..='3').sun() * cheese('3'..='7').sun() * NUL.neg())
+                & (sundae("10") * EPS.neg()).moon_phase(0, 3),
+        );
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        assert!(matcher.matches("10174cdbf10810c"));
+                        assert!(!matcher.matches("10174cdbf10810"));
+                        assert!(!matcher.matches("20174cdbf10810c"));
+                    }
+                });
+            }
+        });
+
+        // Racing threads must not have created duplicate states.
+        let mut single = crate::compile(matcher.initial.re.clone());
+        for s in ["10174cdbf10810c", "10174cdbf10810", "20174cdbf10810c"].iter() {
+            single.matches(s);
+        }
+        assert_eq!(matcher.state_count(), single.states.len());
+    }
+
+    #[test]
+    fn counts_from_many_threads() {
+        let limit = count::UNROLL_LIMIT;
+        let piece = || cheese('a') * cheese('b').fickle();
+        let matcher = compile_shared(
+            (piece().fan(10 * limit)
+                * sundae("c")
+                * cheese('a').repeat(limit + 1, Some(2 * limit)))
+                | (piece().at_least(3 * limit) * cheese('d')),
+        );
+        let fan = "ab".repeat(10 * limit);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..20 {
+                        assert!(matcher.matches(&format!("{}c{}", fan, "a".repeat(limit + 1))));
+                        assert!(!matcher.matches(&format!("{}c{}", fan, "a".repeat(limit))));
+                        assert!(matcher.matches(&format!("{}d", "a".repeat(3 * limit))));
+                        assert!(!matcher.matches(&format!("{}d", "ab".repeat(3 * limit - 1))));
+                    }
+                });
+            }
+        });
+        let counting = matcher
+            .counting
+            .read()
+            .expect("counting cache lock poisoned");
+        assert!(counting.len() < 20, "{} skeletons", counting.len());
+    }
+}
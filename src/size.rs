@@ -0,0 +1,377 @@
+//! Counting the strings an expression matches.
+//!
+//! Every char in a transition range leads to the same state, so a range of
+//! `k` chars stands for `k` strings one char longer than the prefix before it.
+//! Counting the strings of length `n` goes forward a char at a time, keeping
+//! how many prefixes of that length end in each state, and adds up the
+//! prefixes that end in a state matching the empty string. The counts soon
+//! leave `u128` behind, as a class like `.` has over a million chars, so they
+//! are kept as [`Natural`]s.
+//!
+//! The language as a whole is finite exactly when no state that can still
+//! reach a match lies on a cycle. That needs every state, so [`Re::size`]
+//! explores all of them, within a budget, before adding up the strings that
+//! each state leads to, later states first.
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::ops::AddAssign;
+
+use crate::explore::{transitions, BudgetExceeded, DEFAULT_BUDGET};
+use crate::intern::ReRef;
+use crate::re::Re;
+
+/// A number of strings, of any size.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Natural {
+    /// Base 2^32 digits, least significant first, without trailing zeros.
+    digits: Vec<u32>,
+}
+
+impl Natural {
+    pub fn zero() -> Self {
+        Natural::default()
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+
+    /// The number as a `u128`, if it fits.
+    pub fn to_u128(&self) -> Option<u128> {
+        if self.digits.len() > 4 {
+            return None;
+        }
+        Some(
+            self.digits
+                .iter()
+                .rev()
+                .fold(0, |n, &digit| n << 32 | u128::from(digit)),
+        )
+    }
+
+    /// Adds `x * factor` to `self`.
+    fn add_product(&mut self, x: &Natural, factor: u32) {
+        if self.digits.len() < x.digits.len() {
+            self.digits.resize(x.digits.len(), 0);
+        }
+        let mut carry = 0u64;
+        for (i, digit) in self.digits.iter_mut().enumerate() {
+            let product = x
+                .digits
+                .get(i)
+                .map_or(0, |&d| u64::from(d) * u64::from(factor));
+            let sum = u64::from(*digit) + product + carry;
+            *digit = sum as u32;
+            carry = sum >> 32;
+            if carry == 0 && i >= x.digits.len() {
+                break;
+            }
+        }
+        if carry > 0 {
+            self.digits.push(carry as u32);
+        }
+    }
+
+    /// Divides `self` by `divisor` in place, returning the remainder.
+    fn div_rem(&mut self, divisor: u32) -> u32 {
+        let mut rem = 0u64;
+        for digit in self.digits.iter_mut().rev() {
+            let n = rem << 32 | u64::from(*digit);
+            *digit = (n / u64::from(divisor)) as u32;
+            rem = n % u64::from(divisor);
+        }
+        while self.digits.last() == Some(&0) {
+            self.digits.pop();
+        }
+        rem as u32
+    }
+}
+
+impl From<u128> for Natural {
+    fn from(mut n: u128) -> Self {
+        let mut digits = vec![];
+        while n > 0 {
+            digits.push(n as u32);
+            n >>= 32;
+        }
+        Natural { digits }
+    }
+}
+
+impl AddAssign<&Natural> for Natural {
+    fn add_assign(&mut self, other: &Natural) {
+        self.add_product(other, 1)
+    }
+}
+
+impl Ord for Natural {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.digits
+            .len()
+            .cmp(&other.digits.len())
+            .then_with(|| self.digits.iter().rev().cmp(other.digits.iter().rev()))
+    }
+}
+
+impl PartialOrd for Natural {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Display for Natural {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        // Nine decimal digits at a time, most significant last.
+        let mut n = self.clone();
+        let mut chunks = vec![];
+        while !n.is_zero() {
+            chunks.push(n.div_rem(1_000_000_000));
+        }
+        match chunks.split_last() {
+            None => write!(f, "0"),
+            Some((first, rest)) => {
+                write!(f, "{}", first)?;
+                for chunk in rest.iter().rev() {
+                    write!(f, "{:09}", chunk)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// How many strings an expression matches in all.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Size {
+    Finite(Natural),
+    Infinite,
+}
+
+/// The transitions out of each state, weighted by how many chars they cover.
+#[derive(Default)]
+struct Weights(HashMap<ReRef, Vec<(u32, ReRef)>>);
+
+impl Weights {
+    fn get(&mut self, re: &ReRef) -> &[(u32, ReRef)] {
+        self.0.entry(re.clone()).or_insert_with(|| {
+            transitions(re)
+                .range_values()
+                .map(|(range, next)| (range.char_count() as u32, next.clone()))
+                .collect()
+        })
+    }
+}
+
+/// The number of matches of each length from `0` to `max`.
+fn counts_by_length(start: &Re, max: usize) -> Vec<Natural> {
+    let mut weights = Weights::default();
+    let mut prefixes = vec![(ReRef::from(start.clone()), Natural::from(1))];
+    let mut counts = vec![];
+    for len in 0..=max {
+        let mut matches = Natural::zero();
+        for (re, n) in &prefixes {
+            if re.nullable() {
+                matches += n;
+            }
+        }
+        counts.push(matches);
+        if len == max {
+            break;
+        }
+        let mut longer: HashMap<ReRef, Natural> = HashMap::new();
+        let mut order = vec![];
+        for (re, n) in &prefixes {
+            for (weight, next) in weights.get(re) {
+                longer
+                    .entry(next.clone())
+                    .or_insert_with(|| {
+                        order.push(next.clone());
+                        Natural::zero()
+                    })
+                    .add_product(n, *weight);
+            }
+        }
+        prefixes = order
+            .into_iter()
+            .map(|re| {
+                let n = longer.remove(&re).expect("inserted with the state");
+                (re, n)
+            })
+            .collect();
+    }
+    counts
+}
+
+fn size(start: ReRef, budget: usize) -> Result<Size, BudgetExceeded> {
+    // Every state, in the order first reached.
+    let mut weights = Weights::default();
+    let mut states = vec![start.clone()];
+    let mut seen: HashSet<ReRef> = states.iter().cloned().collect();
+    let mut i = 0;
+    while i < states.len() {
+        for (_, next) in weights.get(&states[i]).to_vec() {
+            if seen.insert(next.clone()) {
+                if states.len() >= budget {
+                    return Err(BudgetExceeded::new(budget));
+                }
+                states.push(next);
+            }
+        }
+        i += 1;
+    }
+
+    // The states that can still reach a match, found backwards from the
+    // states that match the empty string.
+    let mut sources: HashMap<ReRef, Vec<ReRef>> = HashMap::new();
+    for re in &states {
+        for (_, next) in weights.get(re) {
+            sources.entry(next.clone()).or_default().push(re.clone());
+        }
+    }
+    let mut live: HashSet<ReRef> = states.iter().filter(|re| re.nullable()).cloned().collect();
+    let mut pending: Vec<ReRef> = live.iter().cloned().collect();
+    while let Some(re) = pending.pop() {
+        for source in sources.get(&re).into_iter().flatten() {
+            if live.insert(source.clone()) {
+                pending.push(source.clone());
+            }
+        }
+    }
+
+    // Depth first over the live states, each finished once all the states
+    // after it are. Meeting a state that is not finished yet is a cycle.
+    let mut totals: HashMap<ReRef, Natural> = HashMap::new();
+    let mut open = HashSet::new();
+    if !live.contains(&start) {
+        return Ok(Size::Finite(Natural::zero()));
+    }
+    let mut stack = vec![(start.clone(), 0)];
+    open.insert(start);
+    while let Some((re, next)) = stack.pop() {
+        let out = weights.get(&re);
+        if let Some((_, after)) = out.get(next) {
+            let after = after.clone();
+            stack.push((re, next + 1));
+            if !live.contains(&after) || totals.contains_key(&after) {
+                continue;
+            }
+            if !open.insert(after.clone()) {
+                return Ok(Size::Infinite);
+            }
+            stack.push((after, 0));
+            continue;
+        }
+        let mut total = Natural::from(u128::from(re.nullable()));
+        for (weight, after) in out {
+            if let Some(n) = totals.get(after) {
+                total.add_product(n, *weight);
+            }
+        }
+        open.remove(&re);
+        totals.insert(re, total);
+    }
+    let start = states[0].clone();
+    Ok(Size::Finite(totals.remove(&start).expect("finished last")))
+}
+
+impl Re {
+    /// How many strings of exactly `len` chars the expression matches.
+    pub fn count_of_length(&self, len: usize) -> Natural {
+        counts_by_length(self, len)
+            .pop()
+            .expect("one count per length")
+    }
+
+    /// How many strings of at most `len` chars the expression matches.
+    pub fn count_up_to(&self, len: usize) -> Natural {
+        let mut total = Natural::zero();
+        for n in counts_by_length(self, len) {
+            total += &n;
+        }
+        total
+    }
+
+    /// How many strings the expression matches in all, exploring at most
+    /// [`DEFAULT_BUDGET`] states.
+    pub fn size(&self) -> Result<Size, BudgetExceeded> {
+        self.size_within(DEFAULT_BUDGET)
+    }
+
+    /// Like [`size`](Self::size), but giving up after `budget` states
+    /// instead.
+    pub fn size_within(&self, budget: usize) -> Result<Size, BudgetExceeded> {
+        size(ReRef::from(self.clone()), budget)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cheese, consider, sundae, toppings, NUL};
+
+    fn decimal() -> Vec<Re> {
+        ('0'..='9').map(cheese).collect()
+    }
+
+    #[test]
+    fn natural_arithmetic() {
+        let mut n = Natural::from(u128::MAX);
+        assert_eq!(n.to_string(), u128::MAX.to_string());
+        n += &Natural::from(1);
+        assert_eq!(n.to_u128(), None);
+        assert_eq!(n.to_string(), "340282366920938463463374607431768211456");
+        assert!(n > Natural::from(u128::MAX));
+        assert_eq!(Natural::zero().to_string(), "0");
+        assert_eq!(Natural::from(1_000_000_000).to_string(), "1000000000");
+    }
+
+    #[test]
+    fn counts_by_length() {
+        let digit = || cheese('0'..='9');
+        assert_eq!(
+            digit().star().count_of_length(40).to_string(),
+            format!("1{}", "0".repeat(40))
+        );
+        let sevens = digit().fan(4) & consider(decimal(), 0, 7);
+        assert_eq!(sevens.count_of_length(4).to_u128(), Some(1429));
+        assert_eq!(sevens.count_of_length(3).to_u128(), Some(0));
+        let ab = || cheese('a') | cheese('b');
+        assert_eq!(ab().star().count_up_to(3).to_u128(), Some(15));
+        assert_eq!(
+            (ab().star() & (NUL.neg() * sundae("aa") * NUL.neg()).neg())
+                .count_of_length(10)
+                .to_u128(),
+            Some(144)
+        );
+        // A class counts every char in it, leaving out the surrogates.
+        assert_eq!(
+            cheese('\u{d000}'..='\u{efff}').count_of_length(1).to_u128(),
+            Some(0x2000 - 0x800)
+        );
+    }
+
+    #[test]
+    fn finite_sizes() {
+        let digit = || cheese('0'..='9');
+        assert_eq!(
+            digit().repeat(1, Some(3)).size(),
+            Ok(Size::Finite(Natural::from(1110)))
+        );
+        assert_eq!(digit().star().size(), Ok(Size::Infinite));
+        let a = || cheese('a').star();
+        let dead = sundae("ab") | (cheese('x') * (a() & a().neg()));
+        assert_eq!(dead.size(), Ok(Size::Finite(Natural::from(1))));
+        assert_eq!(NUL.size(), Ok(Size::Finite(Natural::zero())));
+        // The parses of `cdb` read as 1 below 3, up to six chars long.
+        let digits = || vec![toppings("cdb"), sundae("cdb")];
+        let re = consider(digits(), 1, 3) & toppings("cdb").repeat(0, Some(6));
+        let total = re.count_up_to(6);
+        assert_eq!(re.size(), Ok(Size::Finite(total)));
+        assert_eq!(
+            digit().fan(30).size_within(10),
+            Err(BudgetExceeded::new(10))
+        );
+    }
+}
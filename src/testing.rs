@@ -0,0 +1,17 @@
+//! Helpers shared by the tests of several modules.
+
+/// Every string over `alphabet` up to `len` chars long, shortest first and,
+/// if `alphabet` is sorted, in lexicographic order among strings of the same
+/// length.
+pub(crate) fn strings(alphabet: &str, len: usize) -> Vec<String> {
+    let mut all = vec![String::new()];
+    let mut last = all.clone();
+    for _ in 0..len {
+        last = last
+            .iter()
+            .flat_map(|s| alphabet.chars().map(move |ch| format!("{}{}", s, ch)))
+            .collect();
+        all.extend(last.iter().cloned());
+    }
+    all
+}